@@ -0,0 +1,395 @@
+use polars::prelude::*;
+use anyhow::{Context, Result};
+use chrono::{DateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::future::Future;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+
+use crate::digest::DigestConfig;
+use crate::listing_structs::ListingsContainer;
+use crate::storage::ListingStore;
+use crate::webhook::{WebhookConfig, WebhookNotifier};
+
+
+/// Alerts file used when the config doesn't name one
+const DEFAULT_ALERTS_PATH: &str = "out/alerts.jsonl";
+
+/// Alert rules, where alerts and listing events are sent and the email digest,
+/// read from the file given as `alerts=<path>`
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct AlertConfig {
+    #[serde(default)]
+    pub(crate) rules: Vec<AlertRule>,
+    /// JSON lines file alerts are appended to
+    #[serde(default)]
+    pub(crate) json_path: Option<PathBuf>,
+    #[serde(default)]
+    pub(crate) webhooks: Vec<WebhookConfig>,
+    pub(crate) digest: Option<DigestConfig>,
+}
+
+impl AlertConfig {
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        let config = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read alert config {}", path.display()))?;
+        serde_json::from_str(&config)
+            .with_context(|| format!("Invalid alert config {}", path.display()))
+    }
+
+    /// Stdout, the JSON lines file and a notifier per configured webhook
+    pub(crate) fn notifiers(&self) -> Vec<Box<dyn Notifier>> {
+        let json_path = self.json_path.clone().unwrap_or_else(|| PathBuf::from(DEFAULT_ALERTS_PATH));
+        let mut notifiers: Vec<Box<dyn Notifier>> = vec![
+            Box::new(StdoutNotifier),
+            Box::new(JsonFileNotifier { path: json_path }),
+        ];
+        for webhook in self.webhooks.iter() {
+            notifiers.push(Box::new(WebhookNotifier::new(webhook.clone())));
+        }
+        notifiers
+    }
+}
+
+/// Matched against each price drop recorded this run, every condition set must hold
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct AlertRule {
+    pub(crate) name: String,
+    /// Drop from the previous price of at least this percent
+    pub(crate) min_drop_percent: Option<f64>,
+    /// New price at or below
+    pub(crate) max_price: Option<u32>,
+    /// Listing in one of these zips
+    pub(crate) zips: Option<Vec<u32>>,
+    /// At least the listing's nth price cut, e.g. 3 for a third cut
+    pub(crate) min_cuts: Option<u32>,
+}
+
+impl AlertRule {
+    fn matches(&self, alert: &Alert) -> bool {
+        self.min_drop_percent.is_none_or(|min_drop| -alert.percent_delta >= min_drop)
+            && self.max_price.is_none_or(|max_price| alert.new_price <= max_price)
+            && self.zips.as_ref().is_none_or(|zips| alert.zip.is_some_and(|zip| zips.contains(&zip)))
+            && self.min_cuts.is_none_or(|min_cuts| alert.cut_number >= min_cuts)
+    }
+}
+
+/// A price drop that matched a rule
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Alert {
+    pub(crate) rule: String,
+    pub(crate) run_id: u64,
+    pub(crate) property_id: u64,
+    pub(crate) addr_str: Option<String>,
+    pub(crate) url: Option<String>,
+    pub(crate) zip: Option<u32>,
+    pub(crate) date: DateTime<Utc>,
+    pub(crate) old_price: u32,
+    pub(crate) new_price: u32,
+    /// Negative for a drop
+    pub(crate) percent_delta: f64,
+    /// How many times the listing's price has been cut, including this drop
+    pub(crate) cut_number: u32,
+    pub(crate) list_price: u32,
+}
+
+impl std::fmt::Display for Alert {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}] {} dropped ${} -> ${} ({:.1}%), cut #{}",
+            self.rule,
+            self.addr_str.as_deref().unwrap_or("unknown address"),
+            self.old_price,
+            self.new_price,
+            self.percent_delta,
+            self.cut_number,
+        )?;
+        if let Some(url) = self.url.as_ref() {
+            write!(f, " {}", url)?;
+        }
+        Ok(())
+    }
+}
+
+/// A listing added or repriced this run, sent to notifiers after the datasets are saved
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum ListingEvent {
+    /// Added to the features dataset by `handle_queue`
+    NewListing {
+        run_id: u64,
+        property_id: u64,
+        url: String,
+        addr_str: String,
+        zip: u32,
+        price: u32,
+        beds: i32,
+        baths: i32,
+        sqft: u32,
+        date: DateTime<Utc>,
+    },
+    /// A known listing seen at a different price than last recorded, by `update_existing_redfin`
+    PriceChange {
+        run_id: u64,
+        property_id: u64,
+        addr_str: String,
+        old_price: u32,
+        new_price: u32,
+        date: DateTime<Utc>,
+    },
+}
+
+/// Returned by `Notifier` methods, boxed so notifiers can be kept as trait objects
+pub(crate) type NotifyFuture<'a> = Pin<Box<dyn Future<Output = Result<()>> + 'a>>;
+
+/// Receives the alerts raised by a run and optionally its listing events, implement to send them somewhere new
+///
+/// Sending runs on the async runtime, notifiers doing network IO should await it rather than block
+pub(crate) trait Notifier {
+    fn name(&self) -> &str;
+
+    /// Only called when at least one alert was raised
+    fn notify<'a>(&'a self, alerts: &'a [Alert]) -> NotifyFuture<'a>;
+
+    /// Only called when there are events, notifiers that don't send them can leave this out
+    fn notify_listing_events<'a>(&'a self, _events: &'a [ListingEvent]) -> NotifyFuture<'a> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+pub(crate) struct StdoutNotifier;
+
+impl Notifier for StdoutNotifier {
+    fn name(&self) -> &str {
+        "stdout"
+    }
+
+    fn notify<'a>(&'a self, alerts: &'a [Alert]) -> NotifyFuture<'a> {
+        Box::pin(async move {
+            for alert in alerts {
+                println!("{}", alert);
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Appends one JSON object per alert, so the file keeps every run's alerts
+pub(crate) struct JsonFileNotifier {
+    pub(crate) path: PathBuf,
+}
+
+impl Notifier for JsonFileNotifier {
+    fn name(&self) -> &str {
+        "json file"
+    }
+
+    fn notify<'a>(&'a self, alerts: &'a [Alert]) -> NotifyFuture<'a> {
+        Box::pin(async move { self.append(alerts) })
+    }
+}
+
+impl JsonFileNotifier {
+    fn append(&self, alerts: &[Alert]) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create alerts dir {}", dir.display()))?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)
+            .with_context(|| format!("Failed to open {}", self.path.display()))?;
+        for alert in alerts {
+            writeln!(file, "{}", serde_json::to_string(alert)?)?;
+        }
+        file.sync_all()?;
+        Ok(())
+    }
+}
+
+/// Price drops recorded since `since`, one per rule they match
+///
+/// Cuts are counted over each listing's whole event history, events are sorted by listing and date
+pub(crate) fn evaluate(rules: &[AlertRule], price_events: &DataFrame, features: &DataFrame, since: DateTime<Utc>, run_id: u64) -> PolarsResult<Vec<Alert>> {
+    let listings = features.clone()
+        .lazy()
+        .select([col("property_id"), col("url"), col("zip")])
+        .collect()?;
+    let mut listing_info: HashMap<u64, (Option<String>, Option<u32>)> = HashMap::new();
+    let (ids, urls, zips) = (listings.column("property_id")?.u64()?, listings.column("url")?.utf8()?, listings.column("zip")?.u32()?);
+    for ((id, url), zip) in ids.into_iter().zip(urls).zip(zips) {
+        if let Some(id) = id {
+            listing_info.insert(id, (url.map(String::from), zip));
+        }
+    }
+
+    let ids = price_events.column("property_id")?.u64()?;
+    let addr_strs = price_events.column("addr_str")?.utf8()?;
+    let dates = price_events.column("date")?.cast(&DataType::Int64)?;
+    let dates = dates.i64()?;
+    let old_prices = price_events.column("old_price")?.u32()?;
+    let new_prices = price_events.column("new_price")?.u32()?;
+    let percent_deltas = price_events.column("percent_delta")?.f64()?;
+    let list_prices = price_events.column("list_price")?.u32()?;
+
+    let since = since.timestamp_millis();
+    let mut cuts: HashMap<u64, u32> = HashMap::new();
+    let mut alerts = vec![];
+    for row_idx in 0..price_events.height() {
+        let (Some(property_id), Some(millis), Some(old_price), Some(new_price)) =
+            (ids.get(row_idx), dates.get(row_idx), old_prices.get(row_idx), new_prices.get(row_idx)) else { continue };
+        if new_price >= old_price {
+            continue;
+        }
+        let cut_number = cuts.entry(property_id).or_default();
+        *cut_number += 1;
+        if millis < since {
+            continue;
+        }
+
+        let Some(date) = Utc.timestamp_millis_opt(millis).single() else { continue };
+        let (url, zip) = listing_info.get(&property_id).cloned().unwrap_or_default();
+        let drop = Alert {
+            rule: String::new(),
+            run_id,
+            property_id,
+            addr_str: addr_strs.get(row_idx).map(String::from),
+            url,
+            zip,
+            date,
+            old_price,
+            new_price,
+            percent_delta: percent_deltas.get(row_idx).unwrap_or_default(),
+            cut_number: *cut_number,
+            list_price: list_prices.get(row_idx).unwrap_or(old_price),
+        };
+        for rule in rules.iter().filter(|rule| rule.matches(&drop)) {
+            alerts.push(Alert { rule: rule.name.clone(), ..drop.clone() });
+        }
+    }
+    Ok(alerts)
+}
+
+impl<S: ListingStore> ListingsContainer<S> {
+    /// Evaluates the alert rules against this run's price drops and sends matches to every notifier
+    ///
+    /// A failing notifier is logged and doesn't stop the others
+    pub(crate) async fn raise_alerts(&self) {
+        if self.alert_rules.is_empty() {
+            return;
+        }
+
+        let alerts = match evaluate(&self.alert_rules, &self.price_events, &self.listing_features, self.run.started_at, self.run.run_id) {
+            Ok(alerts) => alerts,
+            Err(e) => {
+                warn!("Failed to evaluate alert rules: {}", e);
+                return;
+            }
+        };
+        info!("{} alerts raised by {} rules", alerts.len(), self.alert_rules.len());
+        if alerts.is_empty() {
+            return;
+        }
+
+        for notifier in self.notifiers.iter() {
+            if let Err(e) = notifier.notify(&alerts).await {
+                warn!("Failed to send alerts to {}: {:#}", notifier.name(), e);
+            }
+        }
+    }
+
+    /// Sends the new listings and price changes recorded since the last call to every notifier
+    pub(crate) async fn send_listing_events(&mut self) {
+        let events = std::mem::take(&mut self.listing_events);
+        if events.is_empty() || self.notifiers.is_empty() {
+            return;
+        }
+
+        info!("Sending {} listing events", events.len());
+        for notifier in self.notifiers.iter() {
+            if let Err(e) = notifier.notify_listing_events(&events).await {
+                warn!("Failed to send listing events to {}: {:#}", notifier.name(), e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::price_events::price_events;
+    use crate::storage::test_frames::{day, features, observations};
+
+    fn rule(name: &str) -> AlertRule {
+        AlertRule { name: name.to_string(), min_drop_percent: None, max_price: None, zips: None, min_cuts: None }
+    }
+
+    /// Listing 1 in 77532 cut three times w/ a raise between, 2 in 77002 cut 5%, 3 not in features
+    fn events() -> (DataFrame, DataFrame) {
+        let history = observations(1, &[
+            (1, 100, day(1)), (1, 90, day(2)), (1, 80, day(3)), (1, 85, day(4)), (1, 70, day(5)),
+            (2, 200, day(1)), (2, 190, day(3)),
+            (3, 300, day(1)), (3, 250, day(4)),
+        ]);
+        (price_events(&history).unwrap(), features(&[(Some(1), 77532), (Some(2), 77002)]))
+    }
+
+    /// (rule, property id, new price, cut number) of each alert
+    fn raised(rules: &[AlertRule], since: DateTime<Utc>) -> Vec<(String, u64, u32, u32)> {
+        let (price_events, features) = events();
+        evaluate(rules, &price_events, &features, since, 7).unwrap().into_iter()
+            .map(|alert| (alert.rule, alert.property_id, alert.new_price, alert.cut_number))
+            .collect()
+    }
+
+    #[test]
+    fn rule_conditions() {
+        let big_drop = AlertRule { min_drop_percent: Some(10.0), ..rule("big_drop") };
+        assert_eq!(raised(&[big_drop], day(1)), vec![
+            (String::from("big_drop"), 1, 90, 1), (String::from("big_drop"), 1, 80, 2), (String::from("big_drop"), 1, 70, 3),
+            (String::from("big_drop"), 3, 250, 1),
+        ]);
+
+        let cheap = AlertRule { max_price: Some(80), ..rule("cheap") };
+        assert_eq!(raised(&[cheap], day(1)), vec![(String::from("cheap"), 1, 80, 2), (String::from("cheap"), 1, 70, 3)]);
+
+        // Listings missing from features have no zip and never match
+        let in_zip = AlertRule { zips: Some(vec![77002, 77003]), ..rule("in_zip") };
+        assert_eq!(raised(&[in_zip], day(1)), vec![(String::from("in_zip"), 2, 190, 1)]);
+
+        let third_cut = AlertRule { min_cuts: Some(3), ..rule("third_cut") };
+        assert_eq!(raised(&[third_cut], day(1)), vec![(String::from("third_cut"), 1, 70, 3)]);
+
+        // Every condition set must hold
+        let both = AlertRule { min_drop_percent: Some(10.0), zips: Some(vec![77002]), ..rule("both") };
+        assert_eq!(raised(&[both], day(1)), vec![]);
+    }
+
+    #[test]
+    fn one_alert_per_matching_rule() {
+        let rules = [AlertRule { max_price: Some(70), ..rule("cheap") }, AlertRule { min_cuts: Some(3), ..rule("third_cut") }];
+        assert_eq!(raised(&rules, day(1)), vec![(String::from("cheap"), 1, 70, 3), (String::from("third_cut"), 1, 70, 3)]);
+    }
+
+    #[test]
+    fn old_events_count_cuts_but_dont_alert() {
+        let any_drop = [rule("any_drop")];
+        assert_eq!(raised(&any_drop, day(4)), vec![
+            (String::from("any_drop"), 1, 70, 3),
+            (String::from("any_drop"), 3, 250, 1),
+        ]);
+        assert_eq!(raised(&any_drop, day(6)), vec![]);
+    }
+
+    #[test]
+    fn alerts_carry_listing_info() {
+        let (price_events, mut features) = events();
+        features.with_column(Series::new("url", [Some("https://www.redfin.com/home/1"), None])).unwrap();
+        let alerts = evaluate(&[AlertRule { min_cuts: Some(3), ..rule("third_cut") }], &price_events, &features, day(1), 7).unwrap();
+        let alert = &alerts[0];
+        assert_eq!((alert.run_id, alert.zip, alert.url.as_deref()), (7, Some(77532), Some("https://www.redfin.com/home/1")));
+        assert_eq!((alert.old_price, alert.list_price, alert.date), (85, 100, day(5)));
+        assert!((alert.percent_delta - (-15.0 / 85.0 * 100.0)).abs() < 1e-9);
+    }
+}
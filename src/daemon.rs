@@ -0,0 +1,214 @@
+use chrono::{DateTime, Duration, Utc};
+use rand::Rng;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::listing_structs::ListingsContainer;
+use crate::searches::SavedSearch;
+use crate::storage::ListingStore;
+
+
+/// Set once SIGTERM or Ctrl-C is received, scrapes check it before each page request
+#[derive(Debug, Clone)]
+pub(crate) struct Shutdown {
+    requested: watch::Receiver<bool>,
+}
+
+impl Shutdown {
+    /// Listens for SIGTERM and Ctrl-C on the tokio runtime
+    ///
+    /// A second signal exits right away w/o saving
+    pub(crate) fn listen() -> Self {
+        let (sender, requested) = watch::channel(false);
+        tokio::spawn(async move {
+            let mut sigterm = match signal(SignalKind::terminate()) {
+                Ok(sigterm) => Some(sigterm),
+                Err(e) => {
+                    warn!("Failed to listen for SIGTERM, only Ctrl-C stops the run: {}", e);
+                    None
+                }
+            };
+
+            let signal = next_signal(&mut sigterm).await;
+            info!("{} received, stopping after the current page. Send again to exit w/o saving", signal);
+            let _ = sender.send(true);
+
+            let signal = next_signal(&mut sigterm).await;
+            warn!("{} received again, exiting w/o saving", signal);
+            std::process::exit(130);
+        });
+        Shutdown { requested }
+    }
+
+    pub(crate) fn is_requested(&self) -> bool {
+        *self.requested.borrow()
+    }
+
+    /// Resolves once shutdown is requested
+    pub(crate) async fn requested(&mut self) {
+        let _ = self.requested.wait_for(|requested| *requested).await;
+    }
+}
+
+/// Name of the next shutdown signal received
+async fn next_signal(sigterm: &mut Option<Signal>) -> &'static str {
+    let sigterm = async {
+        match sigterm.as_mut() {
+            Some(sigterm) => { sigterm.recv().await; }
+            None => std::future::pending::<()>().await,
+        }
+    };
+    tokio::select! {
+        _ = sigterm => "SIGTERM",
+        _ = tokio::signal::ctrl_c() => "Ctrl-C",
+    }
+}
+
+/// A saved search and when the daemon runs it next, jitter included
+struct ScheduledSearch {
+    search: SavedSearch,
+    next_run: DateTime<Utc>,
+}
+
+fn jitter(search: &SavedSearch) -> Duration {
+    match search.jitter_secs {
+        0 => Duration::zero(),
+        max => Duration::seconds(rand::thread_rng().gen_range(0..=max) as i64),
+    }
+}
+
+impl<S: ListingStore> ListingsContainer<S> {
+    pub(crate) fn shutdown_requested(&self) -> bool {
+        self.shutdown.as_ref().is_some_and(|shutdown| shutdown.is_requested())
+    }
+
+    /// Runs the saved searches on their schedules until shutdown is requested
+    ///
+    /// Searches due together share a run. A search whose scheduled time passed while it was
+    /// running skips that time instead of running again straight after
+    pub(crate) async fn run_daemon(&mut self, mut shutdown: Shutdown) {
+        self.shutdown = Some(shutdown.clone());
+        let now = Utc::now();
+        let last_runs = self.saved_search_last_runs();
+        info!("Daemon started, last update: {:?}", self.last_update);
+
+        let mut schedule = vec![];
+        for search in self.saved_searches.iter() {
+            // Searches that never ran pick up from the last update, a fresh store runs them now
+            let next_run = match last_runs.get(&search.name).copied().or(self.last_update) {
+                Some(last_run) => search.next_run(last_run),
+                None => Some(now),
+            };
+            match next_run {
+                // A time missed while the daemon was down is caught up once
+                Some(next_run) => schedule.push(ScheduledSearch { search: search.clone(), next_run: next_run.max(now) + jitter(search) }),
+                None => warn!("Saved search {} has no schedule or interval, not run by the daemon", search.name),
+            }
+        }
+
+        loop {
+            let Some(next_run) = schedule.iter().map(|scheduled| scheduled.next_run).min() else {
+                warn!("No scheduled saved searches left, daemon stopping");
+                return;
+            };
+            info!("Next run at {}", next_run);
+            let wait = (next_run - Utc::now()).to_std().unwrap_or_default();
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {}
+                _ = shutdown.requested() => break,
+            }
+
+            let started = Utc::now();
+            let due: Vec<SavedSearch> = schedule.iter()
+                .filter(|scheduled| scheduled.next_run <= started)
+                .map(|scheduled| scheduled.search.clone())
+                .collect();
+            self.start_run();
+            self.run_searches(&due).await;
+            self.finish_run().await;
+            if shutdown.is_requested() {
+                break;
+            }
+
+            let finished = Utc::now();
+            schedule.retain_mut(|scheduled| {
+                if scheduled.next_run > started {
+                    return true;
+                }
+                let mut next_run = scheduled.search.next_run(started);
+                if let Some(skipped) = next_run.filter(|next_run| *next_run < finished) {
+                    info!("Saved search {} still running at {}, skipping that run", scheduled.search.name, skipped);
+                    next_run = scheduled.search.next_run(finished);
+                }
+                match next_run {
+                    Some(next_run) => {
+                        scheduled.next_run = next_run + jitter(&scheduled.search);
+                        true
+                    }
+                    None => false,
+                }
+            });
+        }
+        info!("Daemon stopped");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listing_structs::{CardSource, HomeAddress, HomeListing, ObservationGranularity};
+    use crate::storage::test_frames::features;
+    use crate::storage::{Dataset, MemoryStore};
+
+    fn listing(property_id: u64) -> HomeListing {
+        HomeListing {
+            property_id,
+            url: format!("https://www.redfin.com/TX/Crosby/12345-Main-St-77532/home/{}", property_id),
+            current_price: 250_000,
+            beds: 3,
+            baths: 2,
+            sqft: 1_800,
+            lot_size: -1,
+            lot_unit: None,
+            address: HomeAddress { street: String::from("12345 Main St"), apt: -1, city: String::from("Crosby"), state: String::from("TX"), zip: 77532 },
+            latitude: None,
+            longitude: None,
+            source: CardSource { url: String::from("https://www.redfin.com/zipcode/77532"), page: 1, position: 0 },
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_saves_partial_run() {
+        let (sender, requested) = watch::channel(false);
+        let mut shutdown = Shutdown { requested };
+        let mut container = ListingsContainer::new(MemoryStore::default(), false, false, ObservationGranularity::default());
+        // Listing 1 is on the market but unseen, a complete scrape would mark it delisted
+        container.listing_features = features(&[(Some(1), 77532)]);
+        container.shutdown = Some(shutdown.clone());
+        container.start_run();
+        container.queue.push(listing(5));
+        assert!(!container.shutdown_requested());
+
+        sender.send(true).unwrap();
+        shutdown.requested().await;
+        assert!(container.shutdown_requested());
+
+        // Zips not started are skipped w/o a request, the queued listings are still handled
+        container.homes_by_zip(77002).await;
+        assert!(container.run.partial);
+        container.handle_queue();
+        container.reconcile_zip(77532).await;
+        container.finish_run().await;
+
+        let runs = container.store.read(Dataset::Runs).unwrap().unwrap();
+        assert_eq!(runs.height(), 1);
+        assert_eq!(runs.column("partial").unwrap().bool().unwrap().get(0), Some(true));
+        let saved = container.store.read(Dataset::Features).unwrap().unwrap();
+        let ids: Vec<Option<u64>> = saved.column("property_id").unwrap().u64().unwrap().into_iter().collect();
+        assert_eq!(ids, vec![Some(1), Some(5)]);
+        // Reconciliation stopped before listing 1 was checked, it keeps its status
+        assert_eq!(saved.column("status").unwrap().utf8().unwrap().get(0), None);
+        assert_eq!(container.store.read(Dataset::History).unwrap().unwrap().height(), 1);
+    }
+}
@@ -0,0 +1,425 @@
+use polars::prelude::*;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use lettre::message::{Mailbox, MultiPart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use serde::Deserialize;
+use tracing::{info, warn};
+use std::fmt::Write;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::listing_structs::{ListingIndex, ListingStatus, ListingsContainer};
+use crate::searches::{watch_list, SavedSearch};
+use crate::storage::ListingStore;
+
+
+/// Holds the time the last digest was sent, when the config doesn't name a file
+const DEFAULT_STATE_PATH: &str = "out/digest_last_sent";
+
+/// How the SMTP connection is secured
+#[derive(Debug, Copy, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SmtpSecurity {
+    /// Plain connection upgraded w/ STARTTLS, usually port 587
+    #[default]
+    Starttls,
+    /// TLS from the start, usually port 465
+    Tls,
+    /// Unencrypted, only for local sinks
+    None,
+}
+
+/// SMTP server and recipients for the market digest, from the alert config's `digest`
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct DigestConfig {
+    pub(crate) smtp_host: String,
+    #[serde(default = "default_smtp_port")]
+    pub(crate) smtp_port: u16,
+    #[serde(default)]
+    pub(crate) smtp_security: SmtpSecurity,
+    pub(crate) username: Option<String>,
+    pub(crate) password: Option<String>,
+    pub(crate) from: String,
+    pub(crate) to: Vec<String>,
+    /// Minimum time between digests, each covers the changes since the last one sent
+    #[serde(default = "default_interval_hours")]
+    pub(crate) interval_hours: u32,
+    pub(crate) state_path: Option<PathBuf>,
+}
+
+fn default_smtp_port() -> u16 {
+    587
+}
+
+fn default_interval_hours() -> u32 {
+    24
+}
+
+impl DigestConfig {
+    fn state_path(&self) -> PathBuf {
+        self.state_path.clone().unwrap_or_else(|| PathBuf::from(DEFAULT_STATE_PATH))
+    }
+
+    /// When the last digest was sent, None if never (or the state file is unreadable)
+    fn last_sent(&self) -> Option<DateTime<Utc>> {
+        let last_sent = fs::read_to_string(self.state_path()).ok()?;
+        DateTime::parse_from_rfc3339(last_sent.trim()).ok().map(|last_sent| last_sent.with_timezone(&Utc))
+    }
+
+    fn record_sent(&self, sent: DateTime<Utc>) -> Result<()> {
+        let path = self.state_path();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&path, sent.to_rfc3339())
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    async fn send(&self, subject: &str, text: String, html: String) -> Result<()> {
+        let mut message = Message::builder()
+            .from(self.from.parse::<Mailbox>().with_context(|| format!("Invalid from address: {}", self.from))?)
+            .subject(subject);
+        for to in self.to.iter() {
+            message = message.to(to.parse::<Mailbox>().with_context(|| format!("Invalid recipient: {}", to))?);
+        }
+        let message = message.multipart(MultiPart::alternative_plain_html(text, html))?;
+
+        let mut transport = match self.smtp_security {
+            SmtpSecurity::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&self.smtp_host)?,
+            SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&self.smtp_host)?,
+            SmtpSecurity::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&self.smtp_host),
+        }
+        .port(self.smtp_port);
+        if let (Some(username), Some(password)) = (self.username.as_ref(), self.password.as_ref()) {
+            transport = transport.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        transport.build().send(message).await
+            .with_context(|| format!("Failed to send digest through {}:{}", self.smtp_host, self.smtp_port))?;
+        Ok(())
+    }
+}
+
+/// A listing in a digest section, `detail` is what changed
+#[derive(Debug, Clone)]
+pub(crate) struct DigestLine {
+    pub(crate) addr_str: String,
+    pub(crate) url: Option<String>,
+    pub(crate) detail: String,
+}
+
+/// Changes to one search's listings over the digest window
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SearchDigest {
+    pub(crate) search: String,
+    pub(crate) new_listings: Vec<DigestLine>,
+    pub(crate) price_drops: Vec<DigestLine>,
+    pub(crate) delistings: Vec<DigestLine>,
+    /// Median percent change over every price change in the window
+    pub(crate) median_change_percent: Option<f64>,
+    pub(crate) price_changes: usize,
+}
+
+/// Digest of a zip's new listings, price drops and delistings since `since`
+///
+/// New listings are priced from the index's last observation
+pub(crate) fn zip_digest(zip: u32, features: &DataFrame, price_events: &DataFrame, index: &ListingIndex, since: DateTime<Utc>) -> PolarsResult<SearchDigest> {
+    let since = lit(since.timestamp_millis());
+    let in_zip = features.clone()
+        .lazy()
+        .filter(col("zip").eq(lit(zip)).and(col("property_id").is_not_null()));
+
+    let mut digest = SearchDigest { search: format!("zipcode {}", zip), ..Default::default() };
+
+    let new_listings = in_zip.clone()
+        .filter(col("first_seen").cast(DataType::Int64).gt_eq(since.clone()))
+        .select([col("property_id"), col("addr_str"), col("url")])
+        .collect()?;
+    let (ids, addr_strs, urls) = (new_listings.column("property_id")?.u64()?, new_listings.column("addr_str")?.utf8()?, new_listings.column("url")?.utf8()?);
+    for ((id, addr_str), url) in ids.into_iter().zip(addr_strs).zip(urls) {
+        let price = id.and_then(|id| index.last_observation(id)).map(|(_, price)| price);
+        digest.new_listings.push(DigestLine {
+            addr_str: addr_str.unwrap_or_default().to_string(),
+            url: url.map(String::from),
+            detail: price.map(|price| format!("${}", price)).unwrap_or_default(),
+        });
+    }
+
+    let off_market = [ListingStatus::Delisted, ListingStatus::Pending, ListingStatus::Sold]
+        .map(|status| status.as_str().to_string());
+    let delistings = in_zip.clone()
+        .filter(col("status").is_in(lit(Series::new("off_market", off_market.to_vec())))
+            .and(col("status_date").cast(DataType::Int64).gt_eq(since.clone())))
+        .select([col("addr_str"), col("url"), col("status")])
+        .collect()?;
+    let (addr_strs, urls, statuses) = (delistings.column("addr_str")?.utf8()?, delistings.column("url")?.utf8()?, delistings.column("status")?.utf8()?);
+    for ((addr_str, url), status) in addr_strs.into_iter().zip(urls).zip(statuses) {
+        digest.delistings.push(DigestLine {
+            addr_str: addr_str.unwrap_or_default().to_string(),
+            url: url.map(String::from),
+            detail: status.unwrap_or_default().to_string(),
+        });
+    }
+
+    let changes = price_events.clone()
+        .lazy()
+        .filter(col("date").cast(DataType::Int64).gt_eq(since))
+        .join(in_zip.select([col("property_id"), col("url")]), [col("property_id")], [col("property_id")], JoinArgs::new(JoinType::Inner))
+        .collect()?;
+    digest.price_changes = changes.height();
+    digest.median_change_percent = changes.column("percent_delta")?.median();
+    let (addr_strs, urls) = (changes.column("addr_str")?.utf8()?, changes.column("url")?.utf8()?);
+    let (old_prices, new_prices, percent_deltas) = (changes.column("old_price")?.u32()?, changes.column("new_price")?.u32()?, changes.column("percent_delta")?.f64()?);
+    for row_idx in 0..changes.height() {
+        let (Some(old_price), Some(new_price)) = (old_prices.get(row_idx), new_prices.get(row_idx)) else { continue };
+        if new_price >= old_price {
+            continue;
+        }
+        digest.price_drops.push(DigestLine {
+            addr_str: addr_strs.get(row_idx).unwrap_or_default().to_string(),
+            url: urls.get(row_idx).map(String::from),
+            detail: format!("${} -> ${} ({:.1}%)", old_price, new_price, percent_deltas.get(row_idx).unwrap_or_default()),
+        });
+    }
+
+    Ok(digest)
+}
+
+/// Digest of a saved search, limited to the listings on its watch list
+pub(crate) fn saved_search_digest(search: &SavedSearch, features: &DataFrame, price_events: &DataFrame, index: &ListingIndex, since: DateTime<Utc>) -> Result<SearchDigest> {
+    let watched = Series::new("watched", watch_list(features, &search.name)?);
+    let watched = features.clone()
+        .lazy()
+        .filter(col("property_id").is_in(lit(watched)))
+        .collect()?;
+    let mut digest = zip_digest(search.zip()?, &watched, price_events, index, since)?;
+    digest.search = search.name.clone();
+    Ok(digest)
+}
+
+fn sections(digest: &SearchDigest) -> [(&'static str, &Vec<DigestLine>); 3] {
+    [
+        ("New listings", &digest.new_listings),
+        ("Price drops", &digest.price_drops),
+        ("Delistings", &digest.delistings),
+    ]
+}
+
+fn median_line(digest: &SearchDigest) -> String {
+    match digest.median_change_percent {
+        Some(median) => format!("Median price change: {:.1}% over {} changes", median, digest.price_changes),
+        None => String::from("Median price change: no price changes"),
+    }
+}
+
+pub(crate) fn render_text(digests: &[SearchDigest], since: DateTime<Utc>) -> String {
+    let mut text = format!("Market changes since {}\n", since.format("%Y-%m-%d %H:%M UTC"));
+    for digest in digests {
+        let _ = writeln!(text, "\n== {} ==", digest.search);
+        for (title, lines) in sections(digest) {
+            let _ = writeln!(text, "{} ({})", title, lines.len());
+            for line in lines {
+                let _ = writeln!(text, "  - {}: {} {}", line.addr_str, line.detail, line.url.as_deref().unwrap_or_default());
+            }
+        }
+        let _ = writeln!(text, "{}", median_line(digest));
+    }
+    text
+}
+
+pub(crate) fn render_html(digests: &[SearchDigest], since: DateTime<Utc>) -> String {
+    let mut html = format!("<html><body>\n<h1>Market changes since {}</h1>\n", since.format("%Y-%m-%d %H:%M UTC"));
+    for digest in digests {
+        let _ = writeln!(html, "<h2>{}</h2>", escape_html(&digest.search));
+        for (title, lines) in sections(digest) {
+            let _ = writeln!(html, "<h3>{} ({})</h3>", title, lines.len());
+            if lines.is_empty() {
+                continue;
+            }
+            html.push_str("<ul>\n");
+            for line in lines {
+                let addr_str = escape_html(&line.addr_str);
+                let addr = match line.url.as_ref() {
+                    Some(url) => format!("<a href=\"{}\">{}</a>", escape_html(url), addr_str),
+                    None => addr_str,
+                };
+                let _ = writeln!(html, "<li>{}: {}</li>", addr, escape_html(&line.detail));
+            }
+            html.push_str("</ul>\n");
+        }
+        let _ = writeln!(html, "<p>{}</p>", median_line(digest));
+    }
+    html.push_str("</body></html>\n");
+    html
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+impl<S: ListingStore> ListingsContainer<S> {
+    /// Emails a digest per saved search, or per zip searched this run w/o saved searches,
+    /// if one is configured and the last was sent at least `interval_hours` ago
+    ///
+    /// Covers the changes since the last digest, or the last interval for the first one
+    pub(crate) async fn send_digest(&self) {
+        let Some(config) = self.digest.as_ref() else { return };
+        let now = Utc::now();
+        let interval = Duration::hours(config.interval_hours as i64);
+        let last_sent = config.last_sent();
+        if let Some(last_sent) = last_sent.filter(|last_sent| now - *last_sent < interval) {
+            info!("Digest not due, last sent {}", last_sent);
+            return;
+        }
+        let since = last_sent.unwrap_or(now - interval);
+
+        let digests = if self.saved_searches.is_empty() {
+            let zips: Vec<u32> = match self.run.search_type.as_deref() {
+                Some("zipcode") => self.run.targets().filter_map(|zip| zip.parse().ok()).collect(),
+                _ => vec![],
+            };
+            zips.iter()
+                .map(|zip| Ok(zip_digest(*zip, &self.listing_features, &self.price_events, &self.listing_index, since)?))
+                .collect::<Result<Vec<SearchDigest>>>()
+        } else {
+            self.saved_searches.iter()
+                .map(|search| saved_search_digest(search, &self.listing_features, &self.price_events, &self.listing_index, since))
+                .collect::<Result<Vec<SearchDigest>>>()
+        };
+        let digests = match digests {
+            Ok(digests) => digests,
+            Err(e) => {
+                warn!("Failed to build digest: {:#}", e);
+                return;
+            }
+        };
+
+        let subject = format!("Market digest: {}", now.format("%Y-%m-%d"));
+        let sent = config.send(&subject, render_text(&digests, since), render_html(&digests, since)).await
+            .and_then(|_| config.record_sent(now));
+        match sent {
+            Ok(_) => info!("Sent digest of {} searches to {} recipients", digests.len(), config.to.len()),
+            Err(e) => warn!("Failed to send digest: {:#}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listing_structs::ObservationGranularity;
+    use crate::price_events::price_events;
+    use crate::storage::test_frames::{features, observations};
+    use crate::storage::MemoryStore;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread::JoinHandle;
+
+    fn config(smtp_port: u16, state_path: PathBuf) -> DigestConfig {
+        DigestConfig {
+            smtp_host: String::from("127.0.0.1"),
+            smtp_port,
+            smtp_security: SmtpSecurity::None,
+            username: None,
+            password: None,
+            from: String::from("scraper@example.com"),
+            to: vec![String::from("a@example.com"), String::from("b@example.com")],
+            interval_hours: 24,
+            state_path: Some(state_path),
+        }
+    }
+
+    /// Plays an SMTP server for one session, returns the commands and message data received
+    fn serve() -> (u16, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut received = vec![];
+            stream.write_all(b"220 localhost ESMTP\r\n").unwrap();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                received.push(line.clone());
+                let reply: &[u8] = match line.to_uppercase() {
+                    command if command.starts_with("EHLO") => b"250 localhost\r\n",
+                    command if command.starts_with("DATA") => {
+                        stream.write_all(b"354 End data w/ <CR><LF>.<CR><LF>\r\n").unwrap();
+                        let mut data = String::new();
+                        loop {
+                            let mut data_line = String::new();
+                            reader.read_line(&mut data_line).unwrap();
+                            if data_line == ".\r\n" {
+                                break;
+                            }
+                            data.push_str(&data_line);
+                        }
+                        received.push(data);
+                        b"250 OK\r\n"
+                    }
+                    command if command.starts_with("QUIT") => {
+                        stream.write_all(b"221 Bye\r\n").unwrap();
+                        break;
+                    }
+                    _ => b"250 OK\r\n",
+                };
+                stream.write_all(reply).unwrap();
+            }
+            received
+        });
+        (port, handle)
+    }
+
+    #[tokio::test]
+    async fn sends_through_smtp() {
+        let dir = tempfile::tempdir().unwrap();
+        let (port, handle) = serve();
+        let config = config(port, dir.path().join("last_sent"));
+        config.send("Market digest", String::from("plain body"), String::from("<p>html body</p>")).await.unwrap();
+
+        let received = handle.join().unwrap();
+        assert!(received.iter().any(|line| line.starts_with("MAIL FROM:<scraper@example.com>")));
+        let rcpts: Vec<&String> = received.iter().filter(|line| line.starts_with("RCPT TO:")).collect();
+        assert_eq!(rcpts.len(), 2);
+        let data = received.iter().find(|line| line.contains("Subject:")).unwrap();
+        assert!(data.contains("Subject: Market digest"));
+        assert!(data.contains("plain body") && data.contains("<p>html body</p>"));
+        assert!(received.last().unwrap().starts_with("QUIT"));
+    }
+
+    #[tokio::test]
+    async fn digest_sent_once_per_interval() {
+        let dir = tempfile::tempdir().unwrap();
+        let (port, handle) = serve();
+        let state_path = dir.path().join("last_sent");
+
+        let mut container = ListingsContainer::new(MemoryStore::default(), false, false, ObservationGranularity::default());
+        container.listing_features = features(&[(Some(1), 77532)]);
+        let now = Utc::now();
+        let history = observations(1, &[(1, 100, now - Duration::hours(3)), (1, 90, now - Duration::hours(2))]);
+        container.price_events = price_events(&history).unwrap();
+        container.run.add_search("zipcode", "77532");
+        container.digest = Some(config(port, state_path.clone()));
+
+        container.send_digest().await;
+        let received = handle.join().unwrap();
+        let data = received.iter().find(|line| line.contains("Subject:")).unwrap();
+        assert!(data.contains("zipcode 77532"));
+        assert!(data.contains("Price drops (1)"));
+        assert!(data.contains("$100 -> $90 (-10.0%)"));
+        let last_sent = container.digest.as_ref().unwrap().last_sent().unwrap();
+        assert!(last_sent >= now);
+
+        // Not due again until the interval has passed, nothing listens on the port now
+        container.send_digest().await;
+        assert_eq!(container.digest.as_ref().unwrap().last_sent(), Some(last_sent));
+    }
+}
@@ -1,8 +1,13 @@
-use scraper::{Html, Selector};
+use scraper::Html;
 use anyhow::Result;
-use tracing::{info, trace, warn};
+use tracing::{info, warn};
 use polars::prelude::*;
-use crate::listing_structs::{ListingsContainer, PriceHistory};
+use crate::listing_structs::{ListingIndex, ListingsContainer, ScrapeRun};
+use crate::price_events::price_events;
+use crate::storage::{conform_to_schema, empty_frame, migrate_legacy_history, Dataset, EmptyReason, HistoryFilter, ListingStore, LoadOutcome, SCHEMA_VERSION};
+
+/// Recorded w/ each scrape run, names the service `request` fetches pages through
+pub(crate) const FETCHER: &str = "scraperapi";
 
 pub(crate) async fn request(target_url: &str) -> Result<Html> {
 
@@ -18,6 +23,8 @@ pub(crate) async fn request(target_url: &str) -> Result<Html> {
     // Send Request
     let response = reqwest::get(scraper_url).await?;
     info!("Response Code: {}", response.status());
+    // Error pages would parse as a page w/o listings
+    let response = response.error_for_status()?;
 
     // Convert resp to HTML str
     let response_str = response.text().await?;
@@ -27,93 +34,191 @@ pub(crate) async fn request(target_url: &str) -> Result<Html> {
     Ok(parsed)
 }
 
-impl ListingsContainer {
+impl<S: ListingStore> ListingsContainer<S> {
+    /// Loads the known listings, runs and price events, always leaving correctly typed frames
+    /// 
+    /// Reports whether each was loaded, migrated, restored or started empty (and why).
+    /// History stays in the store and is scanned as needed, it's only loaded when an older
+    /// schema version has to be upgraded and rewritten on the next save
     pub(crate) fn initialize_datasets(&mut self) {
-            
-        // If local data exists, pull it in
-        // Initalize local feature data
-        let feature_schema = Schema::from_iter(
-            vec![
-                Field::new("beds", DataType::Int32),
-                Field::new("baths", DataType::Int32),
-                Field::new("sqft", DataType::UInt32),
-                Field::new("lot_size", DataType::Int32),
-                Field::new("street", DataType::Utf8),
-                Field::new("apt", DataType::Int32),
-                Field::new("city", DataType::Utf8),
-                Field::new("state", DataType::Utf8),
-                Field::new("zip", DataType::UInt32),
-                Field::new("addr_str", DataType::Utf8),
-            ].into_iter()
-        );
-        if !self.force_refresh {
-            if let Ok(local_feature_data) = CsvReader::from_path("out/listing_features.csv"){
-                if let Ok(features_df) = local_feature_data
-                    .has_header(true)
-                    .with_schema(Some(Arc::new(feature_schema)))
-                    .finish() {
-                    
-                    self.listing_features = features_df;
-                    // TODO: Price History col
-                    // let last = self.data["price_history"].get(0).unwrap(); 
-                    // info!("HERE: {:?}", last);
-                    
-                    // // Check for expected columns
-                    // let expected_cols: Vec<&str> = vec![];
-                    // assert_eq!(expected_cols, self.data.get_column_names());
-
-                    info!("Local data initialized, shape: {:?}", self.listing_features.shape())
-                    
-                }
-            }
-        }
-        // Else assign empty col dataframe
-        else {
-            info!("Local feature data ignored/not found.");
-
-            
-            let feature_cols: Vec<Series> = feature_schema
-                .iter()
-                .map(|(col_name, dtype)| Series::new_empty(&col_name, &dtype))
-                .collect();
-            
-            self.listing_features = DataFrame::new(feature_cols).expect("Failed to create empty default");
+        let version = self.store.schema_version().unwrap_or_else(|e| {
+            warn!("Failed to read schema version, assuming v{}: {:#}", SCHEMA_VERSION, e);
+            SCHEMA_VERSION
+        });
+        if version > SCHEMA_VERSION && !self.force_refresh {
+            panic!("Stored datasets are schema v{}, newer than this build (v{})", version, SCHEMA_VERSION);
         }
+        info!("Stored schema version: v{}", version);
 
-        // Initalize local historical data
-        let hist_schema = Schema::from_iter(
-            vec![
-                Field::new("addr_str", DataType::Utf8), 
-                Field::new("date", DataType::UInt32),
-                Field::new("price", DataType::UInt32),
-            ].into_iter()
-        );
-        if !self.force_refresh {
-            if let Ok(local_hist_data) = CsvReader::from_path("out/listing_history.csv"){
-                if let Ok(hist_df) = local_hist_data
-                    .has_header(true)
-                    .with_schema(Some(Arc::new(hist_schema)))
-                    .finish() {
-                    
-                    self.listing_history = hist_df;
-                    
-
-                    info!("Local data initialized, shape: {:?}", self.listing_history.shape())
-                    
-                }
+        let (features_df, outcome) = self.store.load_known_listings(self.force_refresh, version);
+        self.listing_features = features_df;
+        info!("Listing features {}, shape: {:?}", outcome, self.listing_features.shape());
+
+        // Stored parts are rewritten once in the current schema, or dropped on a force refresh
+        self.replacement_history = match (self.force_refresh, version < SCHEMA_VERSION) {
+            (true, _) => Some(empty_frame(&Dataset::History.schema())),
+            (false, true) => {
+                let (hist_df, outcome) = self.store.load_history(false, version, &self.listing_features);
+                info!("Listing history {}, shape: {:?}", outcome, hist_df.shape());
+                Some(hist_df).filter(|_| matches!(outcome, LoadOutcome::Migrated { .. }))
             }
+            (false, false) => None,
+        };
+
+        // Index known listings once, existence checks are lookups from here on
+        self.listing_index = ListingIndex::from_features(&self.listing_features);
+
+        let (runs, outcome) = self.store.load_runs(version);
+        info!("Scrape runs {}, {} runs", outcome, runs.height());
+        self.last_update = ScrapeRun::last_finished(&runs).unwrap_or_else(|e| {
+            warn!("Failed to read last update from runs: {:#}", e);
+            None
+        });
+        info!("Last update: {:?}", self.last_update);
+
+        let (events, outcome) = self.store.load_price_events(self.force_refresh, version);
+        info!("Price events {}, shape: {:?}", outcome, events.shape());
+        self.price_events = match outcome {
+            // Derived data, rebuilt from history when neither the stored events nor a backup are usable
+            LoadOutcome::Empty(EmptyReason::Rejected(_)) => self.listing_history(None)
+                .and_then(|history| Ok(price_events(&history)?))
+                .unwrap_or_else(|e| {
+                    warn!("Failed to rebuild price events: {:#}", e);
+                    events
+                }),
+            _ => events,
+        };
+    }
+
+    /// Indexes the last recorded price of each listing in a zip before it's scraped
+    ///
+    /// Scanned from disk so only the zip's partitions and needed columns are read
+    pub(crate) fn load_zip_observations(&mut self, zip: u32) {
+        match self.scan_history(&HistoryFilter::zip(zip)) {
+            Ok(Some(history)) => self.listing_index.load_observations(history),
+            Ok(None) => {}
+            Err(e) => warn!("Failed to scan history for zip {}: {:#}", zip, e),
         }
+    }
 
-        else {
-            info!("Local price history data ignored/not found.");
-            
-            
-            let hist_cols: Vec<Series> = hist_schema.iter().map(|(col_name, dtype)| Series::new_empty(&col_name, &dtype)).collect();
-            self.listing_history = DataFrame::new(hist_cols).expect("Failed to create empty listing history DF");
+    /// Stored history w/ only the rows matching filter, None if there is none
+    ///
+    /// Stored history pending a rewrite may not be in the current schema yet,
+    /// its upgraded replacement is filtered instead
+    pub(crate) fn scan_history(&self, filter: &HistoryFilter) -> Result<Option<LazyFrame>> {
+        match self.replacement_history.as_ref() {
+            Some(history) => Ok(Some(filter.apply(history.clone().lazy(), &self.store)?)),
+            None => self.store.scan_history(filter),
         }
-        
+    }
+
+    /// Stored history plus this run's observations of the given listings, every listing's if None
+    ///
+    /// Legacy rows are matched on the address of the listing that claimed them and keyed on its property id
+    pub(crate) fn listing_history(&self, property_ids: Option<&Series>) -> Result<DataFrame> {
+        let stored = match self.scan_history(&HistoryFilter::default())? {
+            Some(history) => {
+                let history = match property_ids {
+                    Some(ids) => {
+                        let addrs = self.listing_features.clone()
+                            .lazy()
+                            .filter(col("property_id").is_in(lit(ids.clone())))
+                            .select([col("addr_str")])
+                            .collect()?;
+                        let is_legacy = col("property_id").is_null().and(col("addr_str").is_in(lit(addrs.column("addr_str")?.clone())));
+                        history.filter(col("property_id").is_in(lit(ids.clone())).or(is_legacy))
+                    }
+                    None => history,
+                };
+                migrate_legacy_history(history.collect()?, &self.listing_features)?
+            }
+            None => empty_frame(&Dataset::History.schema()),
+        };
+        let (mut history, _) = conform_to_schema(&stored, &Dataset::History.schema())?;
 
+        let observations = match property_ids {
+            Some(ids) => self.new_observations.clone()
+                .lazy()
+                .filter(col("property_id").is_in(lit(ids.clone())))
+                .collect()?,
+            None => self.new_observations.clone(),
+        };
+        history.vstack_mut(&observations)?;
+        Ok(history)
     }
 }
 
 // Extraction Helpers
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listing_structs::ObservationGranularity;
+    use crate::storage::test_frames::{day, features, observations, prices};
+    use crate::storage::MemoryStore;
+
+    fn stored(features: &DataFrame, history: &DataFrame) -> MemoryStore {
+        let mut store = MemoryStore::default();
+        store.upsert_features(features).unwrap();
+        store.append_observations(history).unwrap();
+        store.commit().unwrap();
+        store
+    }
+
+    #[test]
+    fn history_scanned_not_loaded() {
+        let store = stored(
+            &features(&[(Some(1), 77532), (Some(2), 77002)]),
+            &observations(1, &[(1, 100, day(1)), (1, 90, day(2)), (2, 200, day(1))]),
+        );
+        let mut container = ListingsContainer::new(store, false, false, ObservationGranularity::default());
+        container.initialize_datasets();
+        assert!(container.replacement_history.is_none());
+        assert_eq!(container.new_observations.height(), 0);
+
+        container.load_zip_observations(77532);
+        assert_eq!(container.listing_index.last_observation(1), Some((day(2), 90)));
+        assert_eq!(container.listing_index.last_observation(2), None);
+    }
+
+    #[test]
+    fn force_refresh_replaces_history() {
+        let store = stored(&features(&[(Some(1), 77532)]), &observations(1, &[(1, 100, day(1))]));
+        let mut container = ListingsContainer::new(store, true, false, ObservationGranularity::default());
+        container.initialize_datasets();
+        container.load_zip_observations(77532);
+        assert_eq!(container.listing_index.last_observation(1), None);
+
+        container.new_observations = observations(2, &[(1, 110, day(3))]);
+        container.save_datasets();
+        assert!(container.replacement_history.is_none());
+        assert_eq!(prices(&container.store.read(Dataset::History).unwrap().unwrap()), vec![(1, 110)]);
+
+        // Later runs on the container, e.g. the daemon's, keep what the refresh saved
+        assert!(!container.force_refresh);
+        container.start_run();
+        container.new_observations = observations(3, &[(1, 105, day(4))]);
+        container.save_datasets();
+        assert_eq!(prices(&container.store.read(Dataset::History).unwrap().unwrap()), vec![(1, 105), (1, 110)]);
+    }
+
+    #[test]
+    fn full_history_keys_legacy_rows() {
+        // Row 0 is a legacy listing since claimed as 5, its history rows only have the address
+        let mut legacy = observations(1, &[(0, 100, day(1))]);
+        legacy.with_column(Series::full_null("property_id", 1, &DataType::UInt64)).unwrap();
+        let store = stored(&features(&[(None, 77532)]), &legacy);
+
+        let mut container = ListingsContainer::new(store, false, false, ObservationGranularity::default());
+        container.initialize_datasets();
+        let mut claimed = features(&[(Some(5), 77532)]);
+        claimed.with_column(legacy.column("addr_str").unwrap().clone()).unwrap();
+        container.listing_features = claimed;
+        container.new_observations = observations(2, &[(5, 90, day(2))]);
+        assert_eq!(prices(&container.listing_history(None).unwrap()), vec![(5, 90), (5, 100)]);
+        let ids = Series::new("ids", [5_u64]);
+        assert_eq!(prices(&container.listing_history(Some(&ids)).unwrap()), vec![(5, 90), (5, 100)]);
+        let ids = Series::new("ids", [6_u64]);
+        assert_eq!(prices(&container.listing_history(Some(&ids)).unwrap()), vec![]);
+    }
+}
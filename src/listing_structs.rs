@@ -1,13 +1,33 @@
 use polars::prelude::*;
-use tracing::{info, trace, warn};
-use std::fs::File;
-use chrono::{Local, DateTime};
+use tracing::{info, warn};
+use crate::alerts::{AlertRule, ListingEvent, Notifier};
+use crate::daemon::Shutdown;
+use crate::digest::DigestConfig;
+use crate::helpers::FETCHER;
+use crate::searches::SavedSearch;
+use crate::storage::{conform_to_schema, empty_frame, timestamp_dtype, timestamp_series, Dataset, ListingStore};
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use chrono::{DateTime, TimeZone, Utc};
 
 
 #[derive(Debug, Clone)]
 pub(crate) enum ExtractionError {
     Price(String),
-    Address(String)
+    Address(String),
+    LotSize(String),
+    PropertyId(String),
+}
+
+impl std::fmt::Display for ExtractionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExtractionError::Price(s) => write!(f, "Unparsable price: {}", s),
+            ExtractionError::Address(s) => write!(f, "Unparsable address: {}", s),
+            ExtractionError::LotSize(s) => write!(f, "Unparsable lot size: {}", s),
+            ExtractionError::PropertyId(s) => write!(f, "Unparsable property id: {}", s),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -19,88 +39,484 @@ pub(crate) struct HomeAddress {
     pub(crate) zip: u32,//[u8; 5],
 }
 
-impl Into<String> for HomeAddress {
-    fn into(self) -> String {
-        match self.apt {
-            -1 => format!("{}, {}, {} {}", self.street, self.city, self.state, self.zip),
+impl From<HomeAddress> for String {
+    fn from(addr: HomeAddress) -> String {
+        match addr.apt {
+            -1 => format!("{}, {}, {} {}", addr.street, addr.city, addr.state, addr.zip),
             _ => {
                 warn!("Formatting address with apt to string");
-                format!("{}, {}, {}, {} {}", self.street, self.apt, self.city, self.state, self.zip)
+                format!("{}, {}, {}, {} {}", addr.street, addr.apt, addr.city, addr.state, addr.zip)
             }
         }
         
     }
 }
 
+/// Whether a listing is on the market, stored as the features `status` column
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum ListingStatus {
+    Active,
+    /// Seen again after being marked off the market
+    Relisted,
+    /// Missing from search results, its listing page shows no contract or sale
+    Delisted,
+    Pending,
+    Sold,
+}
+
+impl ListingStatus {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            ListingStatus::Active => "active",
+            ListingStatus::Relisted => "relisted",
+            ListingStatus::Delisted => "delisted",
+            ListingStatus::Pending => "pending",
+            ListingStatus::Sold => "sold",
+        }
+    }
+
+    /// Stored status strings, null (unreconciled rows) counts as active
+    pub(crate) fn is_off_market(status: Option<&str>) -> bool {
+        matches!(status, Some("delisted" | "pending" | "sold"))
+    }
+}
+
+/// Where on a search results page a listing card was read
+#[derive(Debug, Clone)]
+pub(crate) struct CardSource {
+    /// Search URL the page was requested from
+    pub(crate) url: String,
+    /// 1 based page number
+    pub(crate) page: u32,
+    /// 0 is the page's selected card, the others are numbered from 1 in page order
+    pub(crate) position: u32,
+}
+
 // Define a struct to represent historical price data.
 #[derive(Debug)]
 pub(crate) struct PriceHistory {
     price: u32,
-    date: DateTime<Local>,
+    date: DateTime<Utc>,
+    source: CardSource,
 }
 
 impl PriceHistory {
-    pub(crate) fn new(price: u32, date: DateTime<Local>) -> Self {
+    pub(crate) fn new(price: u32, date: DateTime<Utc>, source: CardSource) -> Self {
         PriceHistory {
             price,
-            date
+            date,
+            source,
         }
     }
+
+    /// History rows w/ exactly the history schema, one per (property id, address, observation)
+    ///
+    /// Every row references the run that recorded it
+    pub(crate) fn to_frame(run_id: u64, observations: Vec<(u64, String, PriceHistory)>) -> DataFrame {
+        let mut property_ids = vec![];
+        let mut addr_strs = vec![];
+        let mut dates = vec![];
+        let mut prices = vec![];
+        let mut source_urls = vec![];
+        let mut pages = vec![];
+        let mut card_positions = vec![];
+        for (property_id, addr_str, observation) in observations {
+            property_ids.push(property_id);
+            addr_strs.push(addr_str);
+            dates.push(observation.date);
+            prices.push(observation.price);
+            source_urls.push(observation.source.url);
+            pages.push(observation.source.page);
+            card_positions.push(observation.source.position);
+        }
+
+        let history_cols = vec![
+            Series::new("property_id", property_ids),
+            Series::new("addr_str", addr_strs),
+            timestamp_series("date", &dates),
+            Series::new("run_id", vec![run_id; prices.len()]),
+            Series::new("price", prices),
+            Series::new("source_url", source_urls),
+            Series::new("page", pages),
+            Series::new("card_position", card_positions),
+        ];
+        let (history_df, _) = conform_to_schema(&DataFrame::new(history_cols).expect("columns same len"), &Dataset::History.schema())
+            .expect("price rows fit the history schema");
+        history_df
+    }
+}
+
+impl From<PriceHistory> for (DateTime<Utc>, u32) {
+    fn from(hist: PriceHistory) -> (DateTime<Utc>, u32) {
+        (hist.date, hist.price)
+    }
 }
 
-impl Into<(DateTime<Local>, u32)> for PriceHistory {
-    fn into(self) -> (DateTime<Local>, u32) {
-        (self.date, self.price)
+/// Unit a lot size was listed in
+/// 
+/// Lot sizes are always stored in sq ft, the listed unit is kept alongside
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum LotUnit {
+    SquareFeet,
+    Acres,
+}
+
+impl LotUnit {
+    pub(crate) const SQFT_PER_ACRE: f64 = 43_560.0;
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            LotUnit::SquareFeet => "sqft",
+            LotUnit::Acres => "acres",
+        }
+    }
+
+    /// Converts a value listed in this unit to whole sq ft
+    pub(crate) fn to_sqft(self, value: f64) -> i32 {
+        match self {
+            LotUnit::SquareFeet => value.round() as i32,
+            LotUnit::Acres => (value * Self::SQFT_PER_ACRE).round() as i32,
+        }
     }
 }
 
 #[derive(Debug)]
 pub(crate) struct HomeListing {
+    /// Redfin property id, the key for both datasets
+    pub(crate) property_id: u64,
+    pub(crate) url: String,
     pub(crate) current_price: u32,
     pub(crate) beds: i32,
     pub(crate) baths: i32,
     pub(crate) sqft: u32,
+    /// Lot size in sq ft, -1 if not listed
     pub(crate) lot_size: i32,
+    /// Unit the lot size was listed in, None if there was no lot stat
+    pub(crate) lot_unit: Option<LotUnit>,
     pub(crate) address: HomeAddress,
+    pub(crate) latitude: Option<f64>,
+    pub(crate) longitude: Option<f64>,
+    /// Page and card the listing was read from
+    pub(crate) source: CardSource,
 }
 
 
-pub(crate) struct ListingsContainer {
+/// How often a seen price becomes a row in the history dataset
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub(crate) enum ObservationGranularity {
+    /// Every run records a row for every listing seen
+    PerRun,
+    /// At most one row per listing per (UTC) day unless the price changes
+    #[default]
+    PerDay,
+    /// Only record when the price differs from the last recorded one
+    OnChange,
+}
+
+impl FromStr for ObservationGranularity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "per_run" => Ok(ObservationGranularity::PerRun),
+            "per_day" => Ok(ObservationGranularity::PerDay),
+            "on_change" => Ok(ObservationGranularity::OnChange),
+            _ => Err(format!("Unknown observation granularity: {} (expected per_run, per_day or on_change)", s)),
+        }
+    }
+}
+
+impl ObservationGranularity {
+    /// Whether a price seen at `date` should be recorded given the listing's last recorded (date, price)
+    pub(crate) fn should_record(self, last: Option<(DateTime<Utc>, u32)>, date: DateTime<Utc>, price: u32) -> bool {
+        let Some((last_date, last_price)) = last else { return true };
+        match self {
+            ObservationGranularity::PerRun => true,
+            ObservationGranularity::PerDay => last_price != price || last_date.date_naive() != date.date_naive(),
+            ObservationGranularity::OnChange => last_price != price,
+        }
+    }
+}
+
+/// In-memory set of listing keys already in the features dataset
+/// 
+/// Built once from the loaded features, kept in sync as rows are added,
+/// so new-vs-known checks per card are hash lookups
+#[derive(Debug, Default)]
+pub(crate) struct ListingIndex {
+    property_ids: HashSet<u64>,
+    /// Addresses of rows saved before listings were keyed on property id
+    legacy_addresses: HashSet<String>,
+    /// Last recorded (date, price) of each listing in the history dataset
+    last_observations: HashMap<u64, (DateTime<Utc>, u32)>,
+}
+
+impl ListingIndex {
+    pub(crate) fn from_features(features: &DataFrame) -> Self {
+        let mut index = ListingIndex::default();
+        let (Ok(ids), Ok(addrs)) = (features.column("property_id"), features.column("addr_str")) else {
+            return index;
+        };
+        let (Ok(ids), Ok(addrs)) = (ids.u64(), addrs.utf8()) else {
+            warn!("Unexpected key column types, listing index left empty");
+            return index;
+        };
+
+        for (id, addr) in ids.into_iter().zip(addrs) {
+            match (id, addr) {
+                (Some(id), _) => { index.property_ids.insert(id); },
+                (None, Some(addr)) => { index.legacy_addresses.insert(addr.to_string()); },
+                (None, None) => warn!("Features row with no property id or address"),
+            }
+        }
+
+        info!("Listing index built: {} listings, {} legacy addresses", index.property_ids.len(), index.legacy_addresses.len());
+        index
+    }
+
+    /// Loads each listing's most recent history row
+    /// 
+    /// Takes a lazy frame so only the key, date and price columns are read from disk
+    pub(crate) fn load_observations(&mut self, history: LazyFrame) {
+        let latest = history
+            .select([col("property_id"), col("date"), col("price")])
+            .filter(col("property_id").is_not_null())
+            .sort("date", SortOptions::default())
+            .group_by([col("property_id")])
+            .agg([col("date").last(), col("price").last()])
+            .collect();
+        let latest = match latest {
+            Ok(df) => df,
+            Err(e) => {
+                warn!("Failed to index last observations: {}", e);
+                return;
+            }
+        };
+
+        // Dates are read back as epoch millis
+        let dates = latest.column("date").and_then(|dates| dates.cast(&DataType::Int64));
+        let (Ok(ids), Ok(dates), Ok(prices)) = (latest.column("property_id"), dates, latest.column("price")) else { return };
+        let (Ok(ids), Ok(millis), Ok(prices)) = (ids.u64(), dates.i64(), prices.u32()) else {
+            warn!("Unexpected history column types, last observations not indexed");
+            return;
+        };
+        for ((id, millis), price) in ids.into_iter().zip(millis).zip(prices) {
+            let date = millis.and_then(|millis| Utc.timestamp_millis_opt(millis).single());
+            if let (Some(id), Some(date), Some(price)) = (id, date, price) {
+                self.last_observations.insert(id, (date, price));
+            }
+        }
+    }
+
+    pub(crate) fn last_observation(&self, property_id: u64) -> Option<(DateTime<Utc>, u32)> {
+        self.last_observations.get(&property_id).copied()
+    }
+
+    pub(crate) fn observe(&mut self, property_id: u64, date: DateTime<Utc>, price: u32) {
+        self.last_observations.insert(property_id, (date, price));
+    }
+
+    pub(crate) fn contains(&self, property_id: u64) -> bool {
+        self.property_ids.contains(&property_id)
+    }
+
+    /// Returns false if the listing was already known
+    pub(crate) fn insert(&mut self, property_id: u64) -> bool {
+        self.property_ids.insert(property_id)
+    }
+
+    pub(crate) fn has_legacy_address(&self, addr_str: &str) -> bool {
+        self.legacy_addresses.contains(addr_str)
+    }
+
+    /// Moves a legacy address over to its property id
+    pub(crate) fn claim(&mut self, addr_str: &str, property_id: u64) {
+        self.legacy_addresses.remove(addr_str);
+        self.property_ids.insert(property_id);
+    }
+}
+
+/// Provenance of one invocation, saved as a row in the runs dataset
+#[derive(Debug, Clone)]
+pub(crate) struct ScrapeRun {
+    /// Start time in epoch millis
+    pub(crate) run_id: u64,
+    pub(crate) started_at: DateTime<Utc>,
+    /// Set when the datasets are saved
+    pub(crate) finished_at: Option<DateTime<Utc>>,
+    /// Stopped early by a shutdown request, what was collected is still saved
+    pub(crate) partial: bool,
+    /// What was searched by (e.g. zipcode), None until a search starts
+    pub(crate) search_type: Option<String>,
+    /// Comma separated values searched for
+    pub(crate) search_target: Option<String>,
+    /// Names of the saved searches run
+    pub(crate) saved_searches: Vec<String>,
+    pub(crate) pages_fetched: u32,
+    /// Page requests that errored, any failure skips the zip's reconciliation
+    pub(crate) pages_failed: u32,
+    /// Distinct listings on any fetched page
+    pub(crate) listings_seen: u32,
+    /// Listings added to the features dataset
+    pub(crate) listings_new: u32,
+    /// Known listings w/ a price observation recorded
+    pub(crate) listings_updated: u32,
+    /// Listing cards that couldn't be extracted, or missing listings whose page failed to load
+    pub(crate) listings_failed: u32,
+}
+
+impl ScrapeRun {
+    pub(crate) fn new(started_at: DateTime<Utc>) -> Self {
+        ScrapeRun {
+            run_id: started_at.timestamp_millis() as u64,
+            started_at,
+            finished_at: None,
+            partial: false,
+            search_type: None,
+            search_target: None,
+            saved_searches: vec![],
+            pages_fetched: 0,
+            pages_failed: 0,
+            listings_seen: 0,
+            listings_new: 0,
+            listings_updated: 0,
+            listings_failed: 0,
+        }
+    }
+
+    /// Adds a search target, a run searching several zips records them all
+    pub(crate) fn add_search(&mut self, search_type: &str, target: &str) {
+        self.search_type = Some(search_type.to_string());
+        self.search_target = Some(match self.search_target.take() {
+            Some(targets) => format!("{},{}", targets, target),
+            None => target.to_string(),
+        });
+    }
+
+    /// Each value searched for this run
+    pub(crate) fn targets(&self) -> impl Iterator<Item = &str> {
+        self.search_target.iter().flat_map(|targets| targets.split(','))
+    }
+
+    /// Latest finish time in a stored runs frame
+    pub(crate) fn last_finished(runs: &DataFrame) -> PolarsResult<Option<DateTime<Utc>>> {
+        let (runs, _) = conform_to_schema(runs, &Dataset::Runs.schema())?;
+        let finished = runs.column("finished_at")?.cast(&DataType::Int64)?;
+        Ok(finished.i64()?.max().and_then(|millis| Utc.timestamp_millis_opt(millis).single()))
+    }
+
+    /// The run as a single row w/ exactly the runs schema
+    pub(crate) fn to_frame(&self) -> DataFrame {
+        let finished_at = self.finished_at.map(|finished_at| finished_at.timestamp_millis());
+        let run_cols = vec![
+            Series::new("run_id", [self.run_id]),
+            timestamp_series("started_at", &[self.started_at]),
+            Series::new("finished_at", [finished_at]).cast(&timestamp_dtype()).expect("millis cast to timestamps"),
+            Series::new("partial", [self.partial]),
+            Series::new("search_type", [self.search_type.clone()]),
+            Series::new("search_target", [self.search_target.clone()]),
+            Series::new("saved_searches", [Some(self.saved_searches.join(",")).filter(|names| !names.is_empty())]),
+            Series::new("pages_fetched", [self.pages_fetched]),
+            Series::new("pages_failed", [self.pages_failed]),
+            Series::new("listings_seen", [self.listings_seen]),
+            Series::new("listings_new", [self.listings_new]),
+            Series::new("listings_updated", [self.listings_updated]),
+            Series::new("listings_failed", [self.listings_failed]),
+            Series::new("fetcher", [FETCHER]),
+            Series::new("crate_version", [env!("CARGO_PKG_VERSION")]),
+        ];
+        let (run_df, _) = conform_to_schema(&DataFrame::new(run_cols).expect("columns same len"), &Dataset::Runs.schema())
+            .expect("run row fits the runs schema");
+        run_df
+    }
+}
+
+pub(crate) struct ListingsContainer<S: ListingStore> {
     pub(crate) queue: Vec<HomeListing>, // replace w/ Multiproducer single consumer??
     pub(crate) listing_features: DataFrame,
-    pub(crate) listing_history: DataFrame,
-    pub(crate) last_update: Option<DateTime<Local>>,
+    /// History rows recorded since the last save, the stored history is scanned as needed rather than loaded
+    pub(crate) new_observations: DataFrame,
+    pub(crate) listing_index: ListingIndex,
+    pub(crate) store: S,
+    /// Property ids seen on any page this run
+    pub(crate) seen_this_run: HashSet<u64>,
+    /// This invocation's provenance, its id is recorded on every new observation
+    pub(crate) run: ScrapeRun,
+    /// Price events loaded w/ the datasets, updated for the listings observed since the last save on every save
+    pub(crate) price_events: DataFrame,
+    /// Evaluated against the run's price drops by `raise_alerts`
+    pub(crate) alert_rules: Vec<AlertRule>,
+    pub(crate) notifiers: Vec<Box<dyn Notifier>>,
+    /// New listings and price changes since the last `send_listing_events`
+    pub(crate) listing_events: Vec<ListingEvent>,
+    /// Emailed by `send_digest` when set
+    pub(crate) digest: Option<DigestConfig>,
+    /// Run by `run_saved_searches` when due
+    pub(crate) saved_searches: Vec<SavedSearch>,
+    /// Scrapes stop before the next page request once it's requested
+    pub(crate) shutdown: Option<Shutdown>,
+    /// History the stored one is replaced w/ on the next save, empty after a force refresh and
+    /// the upgraded stored history after a schema upgrade. The only time the full history is loaded
+    pub(crate) replacement_history: Option<DataFrame>,
+    /// When the last run was saved, loaded from the runs dataset
+    pub(crate) last_update: Option<DateTime<Utc>>,
+    /// Start from empty datasets and re-scrape known listings, cleared once the first run is saved
     pub(crate) force_refresh: bool,
 
     pub(crate) first_page_only: bool,
+    pub(crate) observation_granularity: ObservationGranularity,
 
 }
 
-impl Default for ListingsContainer {
+impl<S: ListingStore + Default> Default for ListingsContainer<S> {
     fn default() -> Self {
-        // TODO: Schema instead ??
-        
         Self { 
             queue: vec![], 
-            listing_features: DataFrame::empty(),
-            listing_history: DataFrame::empty(),
+            listing_features: empty_frame(&Dataset::Features.schema()),
+            new_observations: empty_frame(&Dataset::History.schema()),
+            listing_index: ListingIndex::default(),
+            store: S::default(),
+            seen_this_run: HashSet::new(),
+            run: ScrapeRun::new(Utc::now()),
+            price_events: empty_frame(&Dataset::PriceEvents.schema()),
+            alert_rules: vec![],
+            notifiers: vec![],
+            listing_events: vec![],
+            digest: None,
+            saved_searches: vec![],
+            shutdown: None,
+            replacement_history: None,
             last_update: None,
             force_refresh: false,
-            first_page_only: false
+            first_page_only: false,
+            observation_granularity: ObservationGranularity::default(),
         }
     }
 }
 
-impl ListingsContainer {
-    /// Empty, no columns, dataframe
-    pub(crate) fn new(force_refresh: bool, first_page_only: bool) -> Self {
+impl<S: ListingStore> ListingsContainer<S> {
+    /// Empty datasets w/ schema columns, call initialize_datasets to load local data
+    pub(crate) fn new(store: S, force_refresh: bool, first_page_only: bool, observation_granularity: ObservationGranularity) -> Self {
         ListingsContainer { 
             queue: vec![], 
-            listing_features: DataFrame::empty(),
-            listing_history: DataFrame::empty(), 
+            listing_features: empty_frame(&Dataset::Features.schema()),
+            new_observations: empty_frame(&Dataset::History.schema()),
+            listing_index: ListingIndex::default(),
+            store,
+            seen_this_run: HashSet::new(),
+            run: ScrapeRun::new(Utc::now()),
+            price_events: empty_frame(&Dataset::PriceEvents.schema()),
+            alert_rules: vec![],
+            notifiers: vec![],
+            listing_events: vec![],
+            digest: None,
+            saved_searches: vec![],
+            shutdown: None,
+            replacement_history: None,
             last_update: None,
             force_refresh,
-            first_page_only
+            first_page_only,
+            observation_granularity,
         }
     }
 
@@ -117,16 +533,23 @@ impl ListingsContainer {
 
     /// Adds all listing objects in queue to data as new rows
     /// 
-    /// empties queue
+    /// empties queue, listings already in the index (e.g. seen twice this run) are dropped
     pub(crate) fn handle_queue(&mut self) {
-        
-        
-
+        let queued = self.queue.len();
+        let index = &mut self.listing_index;
+        self.queue.retain(|listing| index.insert(listing.property_id));
+        if self.queue.len() < queued {
+            warn!("Dropped {} duplicate listings from queue", queued - self.queue.len());
+        }
+        self.run.listings_new += self.queue.len() as u32;
 
+        let mut property_id = vec![];
+        let mut url = vec![];
         let mut beds = vec![];
         let mut baths = vec![];
         let mut sqft = vec![];
         let mut lot_size = vec![];
+        let mut lot_unit = vec![];
         // Address Components
         let mut street = vec![];
         let mut apt = vec![];
@@ -134,68 +557,103 @@ impl ListingsContainer {
         let mut state = vec![];
         let mut zip = vec![];
         let mut addr_str: Vec<String> = vec![];
+        let mut latitude = vec![];
+        let mut longitude = vec![];
+        let mut seen = vec![];
 
         // Historical Components
-        let mut prices = vec![];
-        let mut dates = vec![];
+        let mut observations = vec![];
 
         
+        let now = Utc::now();
+
         // Order doesn't matter, can be parrelized
         self.queue.iter().for_each(|listing| {
             
             // Features
+            property_id.push(listing.property_id);
+            url.push(listing.url.clone());
             beds.push(listing.beds);
             baths.push(listing.baths);
             sqft.push(listing.sqft);
             lot_size.push(listing.lot_size);
+            lot_unit.push(listing.lot_unit.map(|unit| unit.as_str()));
             street.push(listing.address.street.clone());
             apt.push(listing.address.apt);
             city.push(listing.address.city.clone());
             state.push(listing.address.state.clone());
-            zip.push(listing.address.zip.clone());
+            zip.push(listing.address.zip);
             // TODO: FIX
             // Clones entire object, then consumes clone to create string
-            addr_str.push(listing.address.clone().into());
+            let listing_addr: String = listing.address.clone().into();
+            addr_str.push(listing_addr.clone());
+            latitude.push(listing.latitude);
+            longitude.push(listing.longitude);
+            seen.push(now);
 
             // Price
-            prices.push(listing.current_price);
-            let unix_time = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() as u32;
-            dates.push(unix_time);
+            observations.push((listing.property_id, listing_addr, PriceHistory::new(listing.current_price, now, listing.source.clone())));
             
         });
+        for listing in self.queue.iter() {
+            self.listing_index.observe(listing.property_id, now, listing.current_price);
+            self.seen_this_run.insert(listing.property_id);
+            self.listing_events.push(ListingEvent::NewListing {
+                run_id: self.run.run_id,
+                property_id: listing.property_id,
+                url: listing.url.clone(),
+                addr_str: listing.address.clone().into(),
+                zip: listing.address.zip,
+                price: listing.current_price,
+                beds: listing.beds,
+                baths: listing.baths,
+                sqft: listing.sqft,
+                date: now,
+            });
+        }
         
 
         // All vecs same len
-        assert!(prices.len() == beds.len() && prices.len() == baths.len() && prices.len() == sqft.len() && prices.len() == lot_size.len());
+        assert!(observations.len() == beds.len() && observations.len() == baths.len() && observations.len() == sqft.len() && observations.len() == lot_size.len());
         
         
+        let property_id = Series::new("property_id", property_id);
+        let url = Series::new("url", url);
         let beds = Series::new("beds", beds);
         let baths = Series::new("baths", baths);
         let sqft = Series::new("sqft", sqft);
         let lot_size = Series::new("lot_size", lot_size);
+        let lot_unit = Series::new("lot_unit", lot_unit);
         let street = Series::new("street", street);
         let apt = Series::new("apt", apt);
         let city = Series::new("city", city);
         let state = Series::new("state", state);
         let zip = Series::new("zip", zip);
         let addr_str = Series::new("addr_str", addr_str);
+        let latitude = Series::new("latitude", latitude);
+        let longitude = Series::new("longitude", longitude);
+        let first_seen = timestamp_series("first_seen", &seen);
+        let last_seen = timestamp_series("last_seen", &seen);
+        let status = Series::new("status", vec![ListingStatus::Active.as_str(); seen.len()]);
+        let status_date = timestamp_series("status_date", &seen);
 
-        let prices = Series::new("price", prices);
-        let dates = Series::new("date", dates);
+        let feature_cols = vec![property_id, url, beds, baths, sqft, lot_size, lot_unit, street, apt, city, state, zip, addr_str, latitude, longitude, first_seen, last_seen, status, status_date];
 
-        let feature_cols = vec![beds, baths, sqft, lot_size, street, apt, city, state, zip, addr_str.clone()];
-        let history_cols = vec![addr_str, dates, prices];
+        // Column order and dtypes come from the dataset schemas
+        let (new_listing_features_df, _) = conform_to_schema(&DataFrame::new(feature_cols).unwrap(), &Dataset::Features.schema())
+            .expect("new listing rows fit the features schema");
 
-        let new_listing_features_df = DataFrame::new(feature_cols).unwrap();
+        let new_history_df = PriceHistory::to_frame(self.run.run_id, observations);
 
-        let new_history_df = DataFrame::new(history_cols).unwrap();
+        // Known listings seen since the last call extend their span
+        self.touch_seen_listings(now);
 
         // Add rows to dataframe
         assert!(self.listing_features.frame_equal_schema(&new_listing_features_df).is_ok());
         self.listing_features = self.listing_features.vstack(&new_listing_features_df).expect("failed to concat new listings");
 
-        assert!(self.listing_history.frame_equal_schema(&new_history_df).is_ok());
-        self.listing_history = self.listing_history.vstack(&new_history_df).expect("Failed to add price history rows");
+        assert!(self.new_observations.frame_equal_schema(&new_history_df).is_ok());
+        self.new_observations = self.new_observations.vstack(&new_history_df).expect("Failed to add price history rows");
 
         // Clear Queue
         self.queue.clear();
@@ -203,29 +661,167 @@ impl ListingsContainer {
 
     }
 
-    pub(crate) fn to_csv(&mut self, dir: &str) {
-        let mut feature_file = File::create(format!("{}/listing_featuress.csv", dir)).expect("file creation failed");
-        let mut history_file = File::create(format!("{}/listing_historys.csv", dir)).unwrap();
+    /// Sets last_seen for every known listing seen this run
+    fn touch_seen_listings(&mut self, now: DateTime<Utc>) {
+        if self.seen_this_run.is_empty() || self.listing_features.height() == 0 { return; }
+
+        let seen_ids = Series::new("seen_ids", self.seen_this_run.iter().copied().collect::<Vec<u64>>());
+        self.listing_features = self.listing_features.clone()
+            .lazy()
+            .with_column(
+                when(col("property_id").is_in(lit(seen_ids)))
+                    .then(lit(now.timestamp_millis()).cast(timestamp_dtype()))
+                    .otherwise(col("last_seen"))
+                    .alias("last_seen")
+            )
+            .collect().expect("Failed to update last_seen");
+    }
 
-        
-        if let Err(e) = CsvWriter::new(&mut feature_file)
-            .finish(&mut self.listing_features) {
-                warn!("Error writing to csv: {}", e);
-            }
-        
-        if let Err(e) = CsvWriter::new(&mut history_file)
-            .finish(&mut self.listing_history) {
-                warn!("Error writing to csv: {}", e);
-            }
+    /// Sets the status of the given listings, dated `now`
+    pub(crate) fn set_listing_status(&mut self, property_ids: Vec<u64>, status: ListingStatus, now: DateTime<Utc>) {
+        if property_ids.is_empty() { return; }
+
+        let is_updated = || col("property_id").is_in(lit(Series::new("updated_ids", property_ids.clone())));
+        self.listing_features = self.listing_features.clone()
+            .lazy()
+            .with_columns([
+                when(is_updated())
+                    .then(lit(status.as_str()))
+                    .otherwise(col("status"))
+                    .alias("status"),
+                when(is_updated())
+                    .then(lit(now.timestamp_millis()).cast(timestamp_dtype()))
+                    .otherwise(col("status_date"))
+                    .alias("status_date"),
+            ])
+            .collect().expect("Failed to update listing status");
+    }
 
-        
-        
+    /// Persists the known listings, this run's new observations and its run row through the store
+    ///
+    /// A replacement history is staged first when set, this run's observations are added after it.
+    /// Price events are derived again for the listings observed since the last save
+    pub(crate) fn save_datasets(&mut self) {
+        self.run.finished_at = Some(Utc::now());
+        self.run.listings_seen = self.seen_this_run.len() as u32;
+        if self.run.partial {
+            warn!("Run {} was stopped early, saving partial results", self.run.run_id);
+        }
+        info!("Run {}: {} pages ({} failed), {} listings seen, {} new, {} updated, {} failed",
+            self.run.run_id, self.run.pages_fetched, self.run.pages_failed, self.run.listings_seen,
+            self.run.listings_new, self.run.listings_updated, self.run.listings_failed);
+
+        let staged = self.store.record_run(&self.run.to_frame())
+            .and_then(|_| self.store.upsert_features(&self.listing_features))
+            .and_then(|_| match self.replacement_history.as_ref() {
+                Some(history) => self.store.replace_history(history),
+                None => Ok(()),
+            })
+            .and_then(|_| self.store.append_observations(&self.new_observations))
+            .and_then(|_| {
+                self.price_events = self.merged_price_events()?;
+                info!("{} price events", self.price_events.height());
+                self.store.replace_derived(Dataset::PriceEvents, &self.price_events)
+            });
+        // Nothing staged is kept on failure, the next save stages this run's observations again
+        if let Err(e) = staged.and_then(|_| self.store.commit()) {
+            warn!("Error saving datasets: {:#}", e);
+            self.store.discard();
+            return;
+        }
+
+        self.replacement_history = None;
+        self.new_observations = empty_frame(&Dataset::History.schema());
+        self.last_update = self.run.finished_at;
+        // The refreshed datasets are stored, later runs on this container (e.g. the daemon's) build on them
+        self.force_refresh = false;
+    }
+
+    /// Starts a new run on a container kept between runs, e.g. by the daemon
+    pub(crate) fn start_run(&mut self) {
+        self.run = ScrapeRun::new(Utc::now());
+        self.seen_this_run.clear();
+    }
+
+    /// Saves the run, then sends its alerts, listing events and the digest
+    pub(crate) async fn finish_run(&mut self) {
+        self.save_datasets();
+        self.raise_alerts().await;
+        self.send_listing_events().await;
+        self.send_digest().await;
     }
 
     pub(crate) fn print_data_head(&self) {
-        println!("{:?}", self.listing_history.head(Some(5)));
+        println!("{:?}", self.new_observations.head(Some(5)));
 
         println!("{:?}", self.listing_features.head(Some(5)));
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::test_frames::{day, features, observations};
+
+    #[test]
+    fn index_known_and_new_listings() {
+        let mut index = ListingIndex::from_features(&features(&[(Some(1), 77532), (Some(2), 77532), (None, 77532)]));
+        assert!(index.contains(1) && index.contains(2));
+        assert!(!index.contains(3));
+        assert!(index.insert(3));
+        assert!(!index.insert(3));
+        assert!(index.contains(3));
+
+        // The legacy row is addressed by its row index
+        let legacy_addr = "2 Old Rd, Crosby, TX 77532";
+        assert!(index.has_legacy_address(legacy_addr));
+        index.claim(legacy_addr, 4);
+        assert!(!index.has_legacy_address(legacy_addr));
+        assert!(index.contains(4));
+    }
+
+    #[test]
+    fn index_last_observations() {
+        let mut index = ListingIndex::default();
+        let history = observations(1, &[(1, 100, day(3)), (1, 90, day(1)), (2, 200, day(2))]);
+        index.load_observations(history.lazy());
+        assert_eq!(index.last_observation(1), Some((day(3), 100)));
+        assert_eq!(index.last_observation(2), Some((day(2), 200)));
+        assert_eq!(index.last_observation(3), None);
+
+        index.observe(1, day(4), 95);
+        assert_eq!(index.last_observation(1), Some((day(4), 95)));
+    }
+
+    #[test]
+    fn granularity_should_record() {
+        let last = Some((day(1), 100));
+        let later_same_day = day(1) + chrono::Duration::hours(6);
+        for granularity in [ObservationGranularity::PerRun, ObservationGranularity::PerDay, ObservationGranularity::OnChange] {
+            assert!(granularity.should_record(None, day(1), 100), "{:?} first observation", granularity);
+            assert!(granularity.should_record(last, later_same_day, 90), "{:?} price change", granularity);
+        }
+
+        assert!(ObservationGranularity::PerRun.should_record(last, later_same_day, 100));
+        assert!(!ObservationGranularity::PerDay.should_record(last, later_same_day, 100));
+        assert!(ObservationGranularity::PerDay.should_record(last, day(2), 100));
+        assert!(!ObservationGranularity::OnChange.should_record(last, day(2), 100));
+    }
+
+    #[test]
+    fn granularity_days_are_utc() {
+        // 23:00 and 01:00 UTC are different days whatever the local time zone
+        let late = Utc.with_ymd_and_hms(2024, 1, 1, 23, 0, 0).unwrap();
+        let early = Utc.with_ymd_and_hms(2024, 1, 2, 1, 0, 0).unwrap();
+        assert!(ObservationGranularity::PerDay.should_record(Some((late, 100)), early, 100));
+    }
+
+    #[test]
+    fn granularity_from_str() {
+        assert_eq!("per_run".parse(), Ok(ObservationGranularity::PerRun));
+        assert_eq!("per_day".parse(), Ok(ObservationGranularity::PerDay));
+        assert_eq!("on_change".parse(), Ok(ObservationGranularity::OnChange));
+        assert!("hourly".parse::<ObservationGranularity>().is_err());
+    }
+}
@@ -1,10 +1,21 @@
-use listing_structs::ListingsContainer;
-use tracing_subscriber;
+use alerts::AlertConfig;
+use daemon::Shutdown;
+use listing_structs::{ListingsContainer, ObservationGranularity};
+use searches::SearchConfig;
+use storage::{FileFormat, FileStore, HistoryLayout, ListingStore, MemoryStore, SqliteStore, StorageBackend};
 use std::env;
 use tracing::info;
+mod alerts;
+mod daemon;
+mod digest;
+mod webhook;
 mod redfin;
 mod listing_structs;
 mod helpers;
+mod price_events;
+mod searches;
+mod schedule;
+mod storage;
 
 
 #[tracing::instrument]
@@ -36,19 +47,83 @@ async fn main() {
         first_page_only = true;
         info!("First page only flag set");
     }
+    let daemon = args.contains(&String::from("daemon"));
 
-    let mut listings_container = ListingsContainer::new(force_refresh, first_page_only);
-    listings_container.initialize_datasets();
-    // println!("{:?}", listings_container.listing_history.get_columns());
-    listings_container.homes_by_zip(77532).await;
+    let observation_granularity = match args.iter().find_map(|arg| arg.strip_prefix("observe=")) {
+        Some(granularity) => granularity.parse().expect("invalid observe= argument"),
+        None => ObservationGranularity::default(),
+    };
+    info!("Observation granularity: {:?}", observation_granularity);
 
-    listings_container.print_data_head();
-    listings_container.to_csv("out");
+    let storage_backend = match args.iter().find_map(|arg| arg.strip_prefix("storage=")) {
+        Some(backend) => backend.parse().expect("invalid storage= argument"),
+        None => StorageBackend::default(),
+    };
+    info!("Storage backend: {:?}", storage_backend);
 
+    let history_layout: HistoryLayout = match args.iter().find_map(|arg| arg.strip_prefix("layout=")) {
+        Some(layout) => layout.parse().expect("invalid layout= argument"),
+        None => HistoryLayout::default(),
+    };
+    info!("History layout: {:?}", history_layout);
 
-    
+    let alert_config = args.iter().find_map(|arg| arg.strip_prefix("alerts="))
+        .map(|path| AlertConfig::load(std::path::Path::new(path)).expect("invalid alerts= config"));
+    if let Some(config) = alert_config.as_ref() {
+        info!("Alert rules: {}", config.rules.len());
+    }
 
-    // println!("{:?}", elems);
+    let search_config = args.iter().find_map(|arg| arg.strip_prefix("searches="))
+        .map(|path| SearchConfig::load(std::path::Path::new(path)).expect("invalid searches= config"));
+    if let Some(config) = search_config.as_ref() {
+        info!("Saved searches: {}", config.searches.len());
+    }
+    if daemon {
+        assert!(search_config.is_some(), "daemon mode runs saved searches, pass searches=<path>");
+        info!("Daemon mode");
+    }
+
+    match storage_backend {
+        StorageBackend::Csv => scrape(FileStore::new("out", FileFormat::Csv).with_layout(history_layout), force_refresh, first_page_only, observation_granularity, alert_config, search_config, daemon).await,
+        StorageBackend::Parquet => scrape(FileStore::new("out", FileFormat::Parquet).with_layout(history_layout), force_refresh, first_page_only, observation_granularity, alert_config, search_config, daemon).await,
+        StorageBackend::Sqlite => {
+            let store = SqliteStore::open(std::path::Path::new("out/listings.db")).expect("Failed to open sqlite store");
+            scrape(store, force_refresh, first_page_only, observation_granularity, alert_config, search_config, daemon).await
+        }
+        StorageBackend::Memory => scrape(MemoryStore::default(), force_refresh, first_page_only, observation_granularity, alert_config, search_config, daemon).await,
+    }
+}
 
-    
+/// Loads local data from the store, scrapes and saves back to it, then raises any alerts
+///
+/// Runs the due saved searches if any are configured, otherwise the default zip.
+/// In daemon mode, runs the saved searches on their schedules until SIGTERM instead.
+/// A run stopped by Ctrl-C or SIGTERM is saved as partial
+async fn scrape<S: ListingStore>(store: S, force_refresh: bool, first_page_only: bool, observation_granularity: ObservationGranularity, alert_config: Option<AlertConfig>, search_config: Option<SearchConfig>, daemon: bool) {
+    let mut listings_container = ListingsContainer::new(store, force_refresh, first_page_only, observation_granularity);
+    if let Some(config) = alert_config {
+        listings_container.notifiers = config.notifiers();
+        listings_container.alert_rules = config.rules;
+        listings_container.digest = config.digest;
+    }
+    if let Some(config) = search_config {
+        listings_container.saved_searches = config.searches;
+    }
+    listings_container.initialize_datasets();
+    // println!("{:?}", listings_container.listing_history.get_columns());
+    let shutdown = Shutdown::listen();
+    if daemon {
+        listings_container.run_daemon(shutdown).await;
+        return;
+    }
+    // Ctrl-C or SIGTERM stops fetching, what was collected is still saved
+    listings_container.shutdown = Some(shutdown);
+    if listings_container.saved_searches.is_empty() {
+        listings_container.homes_by_zip(77532).await;
+    } else {
+        listings_container.run_saved_searches().await;
+    }
+
+    listings_container.print_data_head();
+    listings_container.finish_run().await;
 }
@@ -0,0 +1,152 @@
+use polars::prelude::*;
+use anyhow::Result;
+use tracing::info;
+
+use crate::listing_structs::ListingsContainer;
+use crate::storage::{conform_to_schema, Dataset, ListingStore};
+
+
+const MILLIS_PER_DAY: f64 = 86_400_000.0;
+
+/// Derives the price events dataset, one row per listing per price change
+///
+/// Each listing's history is walked in date order, a row is emitted whenever the price differs
+/// from the previous observation. Rows w/o a property id (unclaimed legacy rows) are left out
+pub(crate) fn price_events(history: &DataFrame) -> PolarsResult<DataFrame> {
+    let by_listing = || [col("property_id")];
+    let millis = || col("date").cast(DataType::Int64);
+
+    let changes = history.clone()
+        .lazy()
+        .filter(col("property_id").is_not_null().and(col("price").is_not_null()).and(col("date").is_not_null()))
+        .sort_by_exprs([col("property_id"), col("date")], [false, false], false, true)
+        .with_columns([
+            col("price").shift(lit(1)).over(by_listing()).alias("old_price"),
+            col("price").first().over(by_listing()).alias("list_price"),
+            millis().first().over(by_listing()).alias("first_millis"),
+        ])
+        .filter(col("old_price").is_not_null().and(col("old_price").neq(col("price"))))
+        // Windows over the changes only, so the previous row is the previous change
+        .with_column(
+            millis().shift(lit(1)).over(by_listing())
+                .fill_null(col("first_millis"))
+                .alias("since_millis")
+        );
+
+    let delta = || col("price").cast(DataType::Int64) - col("old_price").cast(DataType::Int64);
+    let drop = || col("list_price").cast(DataType::Int64) - col("price").cast(DataType::Int64);
+    let events = changes
+        .select([
+            col("property_id"),
+            col("addr_str"),
+            col("date"),
+            col("old_price"),
+            col("price").alias("new_price"),
+            delta().alias("price_delta"),
+            (delta().cast(DataType::Float64) / col("old_price").cast(DataType::Float64) * lit(100.0))
+                .alias("percent_delta"),
+            ((millis() - col("since_millis")).cast(DataType::Float64) / lit(MILLIS_PER_DAY))
+                .alias("days_since_change"),
+            col("list_price"),
+            drop().alias("cumulative_drop"),
+            (drop().cast(DataType::Float64) / col("list_price").cast(DataType::Float64) * lit(100.0))
+                .alias("cumulative_drop_percent"),
+        ])
+        .collect()?;
+
+    Ok(conform_to_schema(&events, &Dataset::PriceEvents.schema())?.0)
+}
+
+impl<S: ListingStore> ListingsContainer<S> {
+    /// The loaded price events w/ those of listings observed since the last save derived again
+    ///
+    /// Only those listings' history is scanned, every other listing keeps its stored events.
+    /// Everything is derived again when the history is being replaced.
+    /// Events are sorted by listing and date
+    pub(crate) fn merged_price_events(&self) -> Result<DataFrame> {
+        if self.replacement_history.is_some() {
+            return Ok(price_events(&self.listing_history(None)?)?);
+        }
+
+        let observed = self.new_observations.column("property_id")?.unique()?;
+        let stored = self.price_events.clone()
+            .lazy()
+            .filter(col("property_id").is_in(lit(observed.clone())).not())
+            .collect()?;
+        if observed.is_empty() {
+            return Ok(stored);
+        }
+
+        let events = price_events(&self.listing_history(Some(&observed))?)?;
+        info!("Derived {} price events for {} observed listings", events.height(), observed.len());
+        Ok(stored.vstack(&events)?
+            .lazy()
+            .sort_by_exprs([col("property_id"), col("date")], [false, false], false, true)
+            .collect()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listing_structs::ObservationGranularity;
+    use crate::storage::test_frames::{day, features, observations};
+    use crate::storage::MemoryStore;
+
+    /// (property id, old price, new price) of each event
+    fn changes(events: &DataFrame) -> Vec<(u64, u32, u32)> {
+        let ids = events.column("property_id").unwrap().u64().unwrap();
+        let old_prices = events.column("old_price").unwrap().u32().unwrap();
+        let new_prices = events.column("new_price").unwrap().u32().unwrap();
+        ids.into_iter().zip(old_prices).zip(new_prices)
+            .map(|((id, old_price), new_price)| (id.unwrap(), old_price.unwrap(), new_price.unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn events_on_price_changes() {
+        let history = observations(1, &[
+            (1, 100, day(1)), (1, 100, day(2)), (1, 90, day(3)), (1, 95, day(5)),
+            (2, 200, day(1)), (2, 200, day(4)),
+        ]);
+        let events = price_events(&history).unwrap();
+        assert_eq!(changes(&events), vec![(1, 100, 90), (1, 90, 95)]);
+
+        let column = |name: &str| events.column(name).unwrap().cast(&DataType::Float64).unwrap();
+        let values = |name: &str| column(name).f64().unwrap().into_no_null_iter().collect::<Vec<f64>>();
+        assert_eq!(values("price_delta"), vec![-10.0, 5.0]);
+        assert_eq!(values("percent_delta"), vec![-10.0, 5.0 / 90.0 * 100.0]);
+        // First change counts from the first observation
+        assert_eq!(values("days_since_change"), vec![2.0, 2.0]);
+        assert_eq!(values("list_price"), vec![100.0, 100.0]);
+        assert_eq!(values("cumulative_drop"), vec![10.0, 5.0]);
+    }
+
+    #[test]
+    fn events_skip_rows_wo_id() {
+        let mut history = observations(1, &[(1, 100, day(1)), (1, 90, day(2))]);
+        history.with_column(Series::full_null("property_id", 2, &DataType::UInt64)).unwrap();
+        assert_eq!(price_events(&history).unwrap().height(), 0);
+    }
+
+    #[test]
+    fn merged_events_only_rederive_observed_listings() {
+        let history = observations(1, &[(1, 100, day(1)), (1, 90, day(2)), (2, 200, day(1))]);
+        let mut store = MemoryStore::default();
+        store.upsert_features(&features(&[(Some(1), 77532), (Some(2), 77532)])).unwrap();
+        store.append_observations(&history).unwrap();
+        store.replace_derived(Dataset::PriceEvents, &price_events(&history).unwrap()).unwrap();
+        store.commit().unwrap();
+
+        let mut container = ListingsContainer::new(store, false, false, ObservationGranularity::default());
+        container.initialize_datasets();
+        assert_eq!(changes(&container.merged_price_events().unwrap()), vec![(1, 100, 90)]);
+
+        container.new_observations = observations(2, &[(2, 180, day(3))]);
+        assert_eq!(changes(&container.merged_price_events().unwrap()), vec![(1, 100, 90), (2, 200, 180)]);
+
+        container.save_datasets();
+        container.new_observations = observations(3, &[(1, 95, day(4))]);
+        assert_eq!(changes(&container.merged_price_events().unwrap()), vec![(1, 100, 90), (1, 90, 95), (2, 200, 180)]);
+    }
+}
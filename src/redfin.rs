@@ -1,12 +1,12 @@
 use scraper::{Html, Selector, ElementRef};
 use tracing::{info, trace, warn};
-use tracing_subscriber;
 use polars::prelude::*;
-use crate::{listing_structs::{PriceHistory, HomeAddress, HomeListing, ListingsContainer, ExtractionError}, helpers};
-use chrono::{Local, DateTime};
-use std::collections::VecDeque;
+use crate::{alerts::ListingEvent, listing_structs::{CardSource, HomeAddress, HomeListing, ListingsContainer, ListingStatus, ExtractionError, LotUnit, PriceHistory}, helpers, storage::{HistoryFilter, ListingStore}};
+use std::collections::{HashMap, VecDeque};
+use chrono::Utc;
 
 #[derive(Debug, Copy, Clone)]
+#[allow(dead_code)]
 enum SearchBy {
     City,
     Address,
@@ -15,6 +15,19 @@ enum SearchBy {
     Zipcode
 }
 
+impl SearchBy {
+    /// Name recorded as a run's search type
+    fn as_str(&self) -> &'static str {
+        match self {
+            SearchBy::City => "city",
+            SearchBy::Address => "address",
+            SearchBy::School => "school",
+            SearchBy::Agent => "agent",
+            SearchBy::Zipcode => "zipcode",
+        }
+    }
+}
+
 
 
 
@@ -24,9 +37,10 @@ impl HomeListing {
     /// 
     /// Currently only returns error if Price is not found
     /// Sets null values if any of category is not found
-    pub(crate) fn new_from_redfin(home_elem: &ElementRef) -> Result<Self, ExtractionError> {
+    pub(crate) fn new_from_redfin(home_elem: &ElementRef, source: CardSource) -> Result<Self, ExtractionError> {
         // extract price
-        let current_price = extract_redfin_price(&home_elem)?;
+        let current_price = extract_redfin_price(home_elem)?;
+        let (property_id, url) = extract_redfin_property_link(home_elem)?;
         // let date = Local::now();
         // let price_history = vec![PriceHistory::new(current_price, date)];
 
@@ -40,6 +54,7 @@ impl HomeListing {
         let mut baths = i32::MAX;
         let mut sqft = u32::MAX;
         let mut lot_size = -1_i32;
+        let mut lot_unit = None;
         for e in stat_elems {
             let stat_str = e.inner_html();
             // Number of bedrooms
@@ -63,26 +78,14 @@ impl HomeListing {
             // Lot stat will be in sqft or acreage
             // may contain "sq ft"
             // check for lot size before house sq ft
-            else if stat_str.contains("lot") {
-                let split_items:Vec<&str> = stat_str.split(" ").collect();
-                
-                // if len split is 4 then lot measured in sqft (Desired)
-                if split_items.len() == 4 {
-                    lot_size = split_items.first().unwrap().replace(",", "").parse::<i32>().expect("failed to parse");
-                }
-
-                // if 3, then measured in acreage
-                else if split_items.len() == 3 {
-
-                    fn acre_to_sqft(acres: f32) -> i32 { (acres * 43460_f32) as i32 }
-
-                    let lot_acres = split_items.first().unwrap().parse::<f32>().expect("failed to parse");
-                    lot_size = acre_to_sqft(lot_acres);
-
+            else if is_lot_stat(&stat_str) {
+                match parse_redfin_lot_size(&stat_str) {
+                    Ok((size, unit)) => {
+                        lot_size = size;
+                        lot_unit = Some(unit);
+                    }
+                    Err(e) => warn!("Skipping lot size: {}", e),
                 }
-
-                else { warn!("Unexpected number of items in stat string: {:?}", split_items); }
-            
             }
             
             // House Sqftage
@@ -111,8 +114,13 @@ impl HomeListing {
         }
         
         // Get Address
-        let addr_str = extract_redfin_address_str(home_elem)?;
         let addr_obj = parse_redfin_address_str(home_elem)?;
+
+        // Get Coordinates
+        let coordinates = extract_redfin_coordinates(home_elem);
+        if coordinates.is_none() {
+            warn!("No coordinates found for listing: {}", String::from(addr_obj.clone()));
+        }
         
         // Checks
         assert_ne!(beds, i32::MAX);
@@ -122,12 +130,18 @@ impl HomeListing {
         trace!("Redfin Listing extracted");
 
         Ok(HomeListing {
+            property_id,
+            url,
             current_price,
             beds,
             baths,
             sqft,
             lot_size,
+            lot_unit,
             address: addr_obj,
+            latitude: coordinates.map(|(lat, _)| lat),
+            longitude: coordinates.map(|(_, long)| long),
+            source,
         })
         
         
@@ -136,46 +150,108 @@ impl HomeListing {
 }
 
 
-impl ListingsContainer {
+impl<S: ListingStore> ListingsContainer<S> {
     
-    //TODO: NOT WORKING! RETURNS TRUE FOR ALL
+    /// Checks the listing index for the card's property id
     pub(crate) fn house_exisits_in_dataset(&self, home_elem: &ElementRef) -> bool {
-        let addr_str = extract_redfin_address_str(home_elem).expect("address failed to extract");
+        // Cards without a property id can't be keyed, treat as new so extraction reports them
+        let Ok((property_id, _)) = extract_redfin_property_link(home_elem) else { return false };
         
-        self.listing_features.clone()
+        self.listing_index.contains(property_id)
+    }
+
+    /// Assigns the card's property id to rows saved before listings were keyed on it
+    /// 
+    /// Legacy rows only have an address, so they are matched on addr_str where property_id is null.
+    /// Stored history rows keep a null id, they're scanned for the listing's last observation
+    pub(crate) fn claim_legacy_listing(&mut self, home_elem: &ElementRef) {
+        let (Ok((property_id, url)), Ok(addr_str)) = (extract_redfin_property_link(home_elem), extract_redfin_address_str(home_elem)) else { return };
+        if !self.listing_index.has_legacy_address(&addr_str) { return; }
+
+        let is_legacy_match = || col("property_id").is_null().and(col("addr_str").eq(lit(addr_str.clone())));
+        let claimed = self.listing_features.clone()
             .lazy()
-            // filter for rows w/ address
-            .filter(col("addr_str").eq(lit(addr_str)))
-            // Count rows
-            .select([count().alias("count")])
-            .collect().expect("created above")
-            .column("count").expect("col created here")
-            .u32().expect("count col always numeric")
-            .get(0).expect("count col always has single row") > 0
-            
-        
+            .filter(is_legacy_match())
+            .collect().expect("filter on known cols")
+            .height();
+        if claimed == 0 {
+            warn!("Legacy address indexed but not found in features: {}", addr_str);
+            return;
+        }
+
+        info!("Assigning property id {} to {} legacy row(s) for {}", property_id, claimed, addr_str);
+        self.listing_features = self.listing_features.clone()
+            .lazy()
+            .with_columns([
+                when(is_legacy_match()).then(lit(url)).otherwise(col("url")).alias("url"),
+                when(is_legacy_match()).then(lit(property_id)).otherwise(col("property_id")).alias("property_id"),
+            ])
+            .collect().expect("Failed to claim legacy features");
+        if let Some(history) = self.replacement_history.as_mut() {
+            *history = history.clone()
+                .lazy()
+                .with_column(when(is_legacy_match()).then(lit(property_id)).otherwise(col("property_id")).alias("property_id"))
+                .collect().expect("Failed to claim legacy history");
+        }
+        self.listing_index.claim(&addr_str, property_id);
+
+        match self.scan_history(&HistoryFilter::default()) {
+            Ok(Some(history)) => {
+                let legacy_rows = history
+                    .filter(is_legacy_match().or(col("property_id").eq(lit(property_id))))
+                    .with_column(lit(property_id).alias("property_id"));
+                self.listing_index.load_observations(legacy_rows);
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Failed to scan legacy history for {}: {:#}", addr_str, e),
+        }
     }
             
 
-    // TODO: Don't add if within same day
-    pub(crate) fn update_existing_redfin(&mut self, home_elem: &ElementRef) -> Result<(), ExtractionError> {
-        let addr_str = extract_redfin_address_str(&home_elem).expect("address found in house_exists fn()");
-        let curr_price = extract_redfin_price(&home_elem).expect("address already found");
-        let unix_time = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() as u32; // Should be u32????
+    /// Records the card's price in the history dataset
+    /// 
+    /// Skipped if the listing was already seen this run or the observation granularity
+    /// collapses it into the listing's last recorded price
+    pub(crate) fn update_existing_redfin(&mut self, home_elem: &ElementRef, source: CardSource) -> Result<(), ExtractionError> {
+        let (property_id, _) = extract_redfin_property_link(home_elem)?;
+        let addr_str = extract_redfin_address_str(home_elem)?;
+        let curr_price = extract_redfin_price(home_elem)?;
+        let now = Utc::now();
 
-        let addr_str = Series::new("addr_str", vec![addr_str]);
-        let price = Series::new("price", vec![curr_price]);
-        let date = Series::new("date", vec![unix_time]);
-        
-        let new_row = DataFrame::new(vec![addr_str, date, price]).expect("Failed to create update dateframe"); // I64 -> u32?????
-        self.listing_history = self.listing_history.vstack(&new_row).expect("Failed to update listing");
+        if !self.seen_this_run.insert(property_id) {
+            trace!("Listing {} already seen this run", property_id);
+            return Ok(());
+        }
+        let last_observation = self.listing_index.last_observation(property_id);
+        if !self.observation_granularity.should_record(last_observation, now, curr_price) {
+            trace!("Collapsing observation of {} into last recorded price", property_id);
+            return Ok(());
+        }
+        self.listing_index.observe(property_id, now, curr_price);
+        self.run.listings_updated += 1;
+        if let Some((_, old_price)) = last_observation.filter(|(_, old_price)| *old_price != curr_price) {
+            self.listing_events.push(ListingEvent::PriceChange {
+                run_id: self.run.run_id,
+                property_id,
+                addr_str: addr_str.clone(),
+                old_price,
+                new_price: curr_price,
+                date: now,
+            });
+        }
+
+        let new_row = PriceHistory::to_frame(self.run.run_id, vec![(property_id, addr_str, PriceHistory::new(curr_price, now, source))]);
+        self.new_observations = self.new_observations.vstack(&new_row).expect("Failed to update listing");
         
         Ok(())
     }
     /// Gets all home listings from a redfin page and adds them as 'HomeListing' objects
     /// to self.queue
-    pub(crate) fn parse_redfin_page(&mut self, parsed_html: &Html) {
+    ///
+    /// Every row added records the page's url, its page number and the card's position
+    pub(crate) fn parse_redfin_page(&mut self, parsed_html: &Html, url: &str, page: u32) {
         let mut listings: Vec<HomeListing> = vec![];
+        let card_source = |position: u32| CardSource { url: url.to_string(), page, position };
 
         let unfocused_home_card_div = r#"div[class="HomeCardContainer defaultSplitMapListView"]"#;
         let unfocused_home_selector = Selector::parse(unfocused_home_card_div).unwrap();
@@ -189,18 +265,24 @@ impl ListingsContainer {
         let focused_home = focused_home.next().unwrap();
 
         // Check if house exists in dataset or if forced entry refresh
+        self.claim_legacy_listing(&focused_home);
         if !self.force_refresh && self.house_exisits_in_dataset(&focused_home) {
             // scrape price and add to price history dataset but not listing dataset
-            if let Err(e) = self.update_existing_redfin(&focused_home) {
+            if let Err(e) = self.update_existing_redfin(&focused_home, card_source(0)) {
                 warn!("Failed to update: {:?}", e);
+                self.run.listings_failed += 1;
             }
             
         }
         // House not found in dataset
         // Add to features && price datasets
         else {
-            if let Ok(listing) = HomeListing::new_from_redfin(&focused_home) {
-                self.queue.push(listing);
+            match HomeListing::new_from_redfin(&focused_home, card_source(0)) {
+                Ok(listing) => listings.push(listing),
+                Err(e) => {
+                    warn!("Skipping Listing: {:?}", e);
+                    self.run.listings_failed += 1;
+                }
             }
         }
         
@@ -210,62 +292,158 @@ impl ListingsContainer {
         let mut i = 1;
         for home_elem in unfocused_homes {
             // If home already exists in dataset
+            self.claim_legacy_listing(&home_elem);
             if !self.force_refresh && self.house_exisits_in_dataset(&home_elem) {
-                if let Err(e) = self.update_existing_redfin(&home_elem) {
+                if let Err(e) = self.update_existing_redfin(&home_elem, card_source(i)) {
                     warn!("Failed to update: {:?}", e);
+                    self.run.listings_failed += 1;
                 }
             }
             // Create new row entry
             else {
-                let listing_res = HomeListing::new_from_redfin(&home_elem);
+                let listing_res = HomeListing::new_from_redfin(&home_elem, card_source(i));
                 if let Err(e) =  listing_res {
                     warn!("Skipping Listing: {:?}", e);
+                    self.run.listings_failed += 1;
                 } 
-                else { self.queue.push(listing_res.expect("conditioned")); }
+                else { listings.push(listing_res.expect("conditioned")); }
             }    
             
             i += 1;
         }
 
+        self.enqueue(&mut listings);
+
         info!("Number of houses on page found: {}", i);
     }
     
     /// Gets all redfin home listings for a given zipcode
     /// 
-    /// Calls parse_redfin_page on all found pages then handles all elements in self.queue
+    /// Calls parse_redfin_page on all found pages then handles all elements in self.queue.
+    /// Failed pages are skipped, the zip is only reconciled if every page was fetched.
+    /// Once shutdown is requested no more pages are fetched, what was parsed is still handled
     pub(crate) async fn homes_by_zip(&mut self, zipcode: u32) {
+        if self.shutdown_requested() {
+            info!("Shutdown requested, skipping zip {}", zipcode);
+            self.run.partial = true;
+            return;
+        }
+        self.load_zip_observations(zipcode);
+        self.run.add_search(SearchBy::Zipcode.as_str(), &zipcode.to_string());
 
         // First run gets number of pages
         let url = url_builder(SearchBy::Zipcode, zipcode, None);
-        let request_result = helpers::request(&url).await;
-        if request_result.is_err() { 
-            let e = request_result.unwrap_err();
-            warn!("Request Error: {}", &e);
-            panic!("Request Error: {}", e); 
-        }
-
-        let response = request_result.expect("conditioned");
+        let response = match helpers::request(&url).await {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("Request Error, skipping zip {}: {}", zipcode, e);
+                self.run.pages_failed += 1;
+                return;
+            }
+        };
+        self.run.pages_fetched += 1;
         
         let page_count = get_redfin_page_count(&response);
-        self.parse_redfin_page(&response);
+        self.parse_redfin_page(&response, &url, 1);
         self.handle_queue();
 
-        if !self.first_page_only {
-            for page_num in 2..=page_count {
-                let url = url_builder(SearchBy::Zipcode, zipcode, Some(page_num));
-                let request_result = helpers::request(&url).await;
-                if request_result.is_err() { 
-                    let e = request_result.expect_err("Conditioned for");
-                    warn!("Request Error: {}", &e);
-                    panic!("Request Error: {}", e); 
+        if self.first_page_only {
+            info!("First page only, zip {} not reconciled", zipcode);
+            return;
+        }
+
+        let mut pages_failed = 0;
+        let mut stopped = false;
+        // Unknown page counts only scrape the first page
+        let last_page = page_count.unwrap_or(1);
+        for page_num in 2..=last_page {
+            if self.shutdown_requested() {
+                info!("Shutdown requested, stopping zip {} before page {}", zipcode, page_num);
+                self.run.partial = true;
+                stopped = true;
+                break;
+            }
+            let url = url_builder(SearchBy::Zipcode, zipcode, Some(page_num));
+            let response = match helpers::request(&url).await {
+                Ok(response) => response,
+                Err(e) => {
+                    warn!("Request Error, skipping page {}: {}", page_num, e);
+                    pages_failed += 1;
+                    continue;
                 }
-                let response = request_result.expect("conditioned");
-                self.parse_redfin_page(&response);
             };
+            self.run.pages_fetched += 1;
+            self.parse_redfin_page(&response, &url, page_num as u32);
+        };
+        self.run.pages_failed += pages_failed;
 
-            self.handle_queue();
+        self.handle_queue();
+
+        if zip_scrape_complete(zipcode, page_count, pages_failed, stopped) {
+            self.reconcile_zip(zipcode).await;
+        }
+    }
+
+    /// Compares the zip's listings seen this run against those stored as on the market
+    ///
+    /// Missing listings are marked delisted, pending or sold from their listing page,
+    /// ones whose page fails to load keep their status and count as failed.
+    /// Off market listings seen again are marked relisted.
+    /// Only valid after every page of the zip was fetched
+    pub(crate) async fn reconcile_zip(&mut self, zipcode: u32) {
+        let now = Utc::now();
+        let zip_listings = self.listing_features.clone()
+            .lazy()
+            .filter(col("zip").eq(lit(zipcode)).and(col("property_id").is_not_null()))
+            .select([col("property_id"), col("url"), col("status")])
+            .collect()
+            .expect("Failed to select zip listings");
+        let ids = zip_listings.column("property_id").and_then(|ids| ids.u64()).expect("schema dtype");
+        let urls = zip_listings.column("url").and_then(|urls| urls.utf8()).expect("schema dtype");
+        let statuses = zip_listings.column("status").and_then(|statuses| statuses.utf8()).expect("schema dtype");
+
+        let mut missing = vec![];
+        let mut relisted = vec![];
+        for ((id, url), status) in ids.into_iter().zip(urls).zip(statuses) {
+            let Some(id) = id else { continue };
+            match (self.seen_this_run.contains(&id), ListingStatus::is_off_market(status)) {
+                (true, true) => relisted.push(id),
+                (false, false) => missing.push((id, url.map(String::from))),
+                _ => {}
+            }
+        }
+
+        // Each missing listing's own page says whether it went under contract or sold
+        let mut off_market: HashMap<ListingStatus, Vec<u64>> = HashMap::new();
+        for (id, url) in missing {
+            // Listings not checked yet keep their status until the next reconciliation
+            if self.shutdown_requested() {
+                info!("Shutdown requested, stopping reconciliation of zip {}", zipcode);
+                self.run.partial = true;
+                break;
+            }
+            let status = match url {
+                Some(url) => match helpers::request(&url).await {
+                    Ok(page) => listing_page_status(&page),
+                    Err(e) => {
+                        warn!("Failed to fetch missing listing {}, status unchanged: {}", id, e);
+                        self.run.listings_failed += 1;
+                        continue;
+                    }
+                },
+                None => ListingStatus::Delisted,
+            };
+            off_market.entry(status).or_default().push(id);
+        }
+
+        let off_market_counts: Vec<String> = off_market.iter()
+            .map(|(status, ids)| format!("{} {}", ids.len(), status.as_str()))
+            .collect();
+        info!("Reconciled zip {}: {} relisted, off market: [{}]", zipcode, relisted.len(), off_market_counts.join(", "));
+        self.set_listing_status(relisted, ListingStatus::Relisted, now);
+        for (status, ids) in off_market {
+            self.set_listing_status(ids, status, now);
         }
-        
     }
 
     
@@ -284,8 +462,8 @@ pub(crate) fn url_builder(search_category: SearchBy, search_target: u32, page_nu
         SearchBy::Zipcode => format!("zipcode/{}", search_target), // ONLY USE THIS ONE
         SearchBy::Address => format!("address/{}", search_target), // WRONG BUT FIXABLE
         SearchBy::City => format!("city/randnum/TX/{}", search_target), // WRONG idk randnum
-        SearchBy::Agent => format!("Idk what this is"),
-        SearchBy::School => format!("don'tuse"),
+        SearchBy::Agent => String::from("Idk what this is"),
+        SearchBy::School => String::from("don'tuse"),
         
     };
     base_url = format!("{}{}", base_url, target_query);
@@ -305,43 +483,188 @@ pub(crate) fn url_builder(search_category: SearchBy, search_target: u32, page_nu
     base_url
 }
 
-pub(crate) fn get_redfin_page_count(parsed_html: &Html) -> u8 {
+/// Total number of pages from the "Viewing page 1 of 12" text, None if it's missing or unparsable
+pub(crate) fn get_redfin_page_count(parsed_html: &Html) -> Option<u8> {
     
     // Define Target Span & build html selector
     let page_count_span = r#"span[class="pageText"]"#;
     let page_count_selector = Selector::parse(page_count_span).unwrap();
 
-    // Find target span
-    let page_count_container = parsed_html.select(&page_count_selector).next().unwrap();
+    // Find target span & get its inner html
+    let page_count = parsed_html.select(&page_count_selector).next()
+        .and_then(|container| parse_page_count(&container.inner_html()));
     
-    // Get target inner html
-    let page_count_str: String = page_count_container.inner_html();
-    
-    // take last char representing n and parse
-    let page_count: u8 = page_count_str.chars().last().unwrap()
-        .to_digit(10).unwrap().try_into().unwrap();
-    
-    info!("Number of pages found: {}", page_count);
+    match page_count {
+        Some(page_count) => info!("Number of pages found: {}", page_count),
+        None => warn!("Page count not found"),
+    }
     page_count
 }
 
+/// Whether a zip's scrape saw every listing on the market, so the stored ones it didn't see are missing
+///
+/// Not if it was stopped early, its page count is unknown or any page failed
+fn zip_scrape_complete(zipcode: u32, page_count: Option<u8>, pages_failed: u32, stopped: bool) -> bool {
+    match (page_count, pages_failed) {
+        _ if stopped => info!("Zip {} not reconciled, scrape stopped early", zipcode),
+        (None, _) => warn!("Page count unknown, zip {} not reconciled", zipcode),
+        (Some(_), 0) => return true,
+        (Some(page_count), n) => warn!("{} of {} pages failed, zip {} not reconciled", n, page_count, zipcode),
+    }
+    false
+}
+
+/// Parses the whole number after "of", e.g. 12 from "Viewing page 1 of 12"
+fn parse_page_count(page_text: &str) -> Option<u8> {
+    let (_, page_count) = page_text.rsplit_once(" of ")?;
+    page_count.trim().parse().ok()
+}
+
 fn extract_redfin_price(home_elem: &ElementRef) -> Result<u32, ExtractionError> { 
     let price_id = r#"span[class="homecardV2Price"]"#;
     let price_sel = Selector::parse(price_id).unwrap();
-    let price_str = home_elem.select(&price_sel).next().unwrap().inner_html();
-    let parsed_price = {
-        let cleaned_price_str = (&price_str[1..]).replace(',', "");
-        cleaned_price_str.parse::<u32>().map_err(|_| ExtractionError::Price(price_str))
+    let price_str = home_elem.select(&price_sel).next()
+        .ok_or_else(|| ExtractionError::Price(String::from("no price in card")))?
+        .inner_html();
+    let cleaned_price_str = price_str.trim_start_matches('$').replace(',', "");
+    cleaned_price_str.parse::<u32>().map_err(|_| ExtractionError::Price(price_str))
+}
+
+fn is_lot_stat(stat_str: &str) -> bool {
+    let lowered = stat_str.to_lowercase();
+    lowered.contains("lot")
+        || lowered.contains("acre")
+        || lowered.split_whitespace().any(|token| token == "ac")
+}
+
+/// Parses a lot stat ("7,405 sq ft lot", "0.26 acres", "1.5 ac", "— sq ft lot")
+/// 
+/// Returns the lot size in sq ft, or -1 when redfin has no value, along with the listed unit
+fn parse_redfin_lot_size(stat_str: &str) -> Result<(i32, LotUnit), ExtractionError> {
+    let lowered = stat_str.to_lowercase();
+    let tokens: Vec<&str> = lowered.split_whitespace().collect();
+
+    // Check for sq ft before acreage, "acre" never appears in a sq ft stat
+    let unit = if lowered.contains("sq ft") || lowered.contains("sqft") {
+        LotUnit::SquareFeet
+    } else if lowered.contains("acre") || tokens.contains(&"ac") {
+        LotUnit::Acres
+    } else {
+        return Err(ExtractionError::LotSize(stat_str.to_string()));
+    };
+
+    let value_str = tokens.first()
+        .ok_or_else(|| ExtractionError::LotSize(stat_str.to_string()))?
+        .replace(',', "");
+
+    // Redfin uses an em dash when the lot size is unknown
+    if value_str == "—" || value_str == "-" {
+        return Ok((-1, unit));
+    }
+
+    let value = value_str.parse::<f64>().map_err(|_| ExtractionError::LotSize(stat_str.to_string()))?;
+    Ok((unit.to_sqft(value), unit))
+}
+
+/// Reads a listing page's status banner, anything not under contract or sold counts as delisted
+fn listing_page_status(listing_page: &Html) -> ListingStatus {
+    let banner_sel = Selector::parse("div.ListingStatusBannerSection").expect("valid above html");
+    let banner = listing_page.select(&banner_sel)
+        .map(|banner| banner.text().collect::<String>().to_uppercase())
+        .next()
+        .unwrap_or_default();
+
+    if banner.contains("SOLD") {
+        ListingStatus::Sold
+    } else if banner.contains("PENDING") || banner.contains("CONTINGENT") || banner.contains("UNDER CONTRACT") {
+        ListingStatus::Pending
+    } else {
+        ListingStatus::Delisted
+    }
+}
+
+/// Gets the redfin property id and listing url from the card's home link
+/// 
+/// Links look like "/TX/Crosby/123-Main-St-77532/home/12345678",
+/// units are "/TX/Houston/100-Main-St-77002/unit-12/home/12345679"
+fn extract_redfin_property_link(home_elem: &ElementRef) -> Result<(u64, String), ExtractionError> {
+    let link_id = r#"a[href*="/home/"]"#;
+    let link_sel = Selector::parse(link_id).expect("valid above html");
+    let href = home_elem.select(&link_sel)
+        .find_map(|link| link.value().attr("href"))
+        .ok_or_else(|| ExtractionError::PropertyId(String::from("no home link in card")))?;
+
+    let property_id = href.rsplit("/home/").next()
+        .map(|tail| tail.chars().take_while(|c| c.is_ascii_digit()).collect::<String>())
+        .and_then(|digits| digits.parse::<u64>().ok())
+        .ok_or_else(|| ExtractionError::PropertyId(href.to_string()))?;
+
+    let url = match href.starts_with("http") {
+        true => href.to_string(),
+        false => format!("https://www.redfin.com{}", href),
     };
 
-    parsed_price
+    Ok((property_id, url))
+}
+
+/// Every home card embeds its schema.org data as JSON-LD script tags
+fn extract_redfin_json_ld(home_elem: &ElementRef) -> Vec<serde_json::Value> {
+    let script_id = r#"script[type="application/ld+json"]"#;
+    let script_sel = Selector::parse(script_id).expect("valid above html");
+    home_elem.select(&script_sel)
+        .filter_map(|script| {
+            let script_str = script.text().collect::<String>();
+            match serde_json::from_str(&script_str) {
+                Ok(value) => Some(value),
+                Err(e) => {
+                    warn!("Unparsable JSON-LD in home card: {}", e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Finds the first schema.org GeoCoordinates object in the card's JSON-LD
+/// 
+/// Returns (latitude, longitude)
+fn extract_redfin_coordinates(home_elem: &ElementRef) -> Option<(f64, f64)> {
+    fn find_geo(value: &serde_json::Value) -> Option<(f64, f64)> {
+        match value {
+            serde_json::Value::Object(map) => {
+                if let Some(geo) = map.get("geo") {
+                    let lat = geo.get("latitude").and_then(json_f64);
+                    let long = geo.get("longitude").and_then(json_f64);
+                    if let (Some(lat), Some(long)) = (lat, long) {
+                        return Some((lat, long));
+                    }
+                }
+                map.values().find_map(find_geo)
+            }
+            serde_json::Value::Array(items) => items.iter().find_map(find_geo),
+            _ => None,
+        }
+    }
+
+    // Coordinates are sometimes serialized as strings
+    fn json_f64(value: &serde_json::Value) -> Option<f64> {
+        match value {
+            serde_json::Value::Number(n) => n.as_f64(),
+            serde_json::Value::String(s) => s.parse().ok(),
+            _ => None,
+        }
+    }
+
+    extract_redfin_json_ld(home_elem).iter().find_map(find_geo)
 }
 
 fn extract_redfin_address_str(home_elem: &ElementRef) -> Result<String, ExtractionError> {
         // Get Address
         let address_id = r#"span[class="collapsedAddress primaryLine"]"#;
         let address_sel = Selector::parse(address_id).expect("valid above html");
-        let address_str = home_elem.select(&address_sel).next().unwrap().inner_html(); // TODO: handle address not found error
+        let address_str = home_elem.select(&address_sel).next()
+            .ok_or_else(|| ExtractionError::Address(home_elem.html()))?
+            .inner_html();
         Ok(address_str)
         
     
@@ -391,3 +714,188 @@ fn parse_redfin_address_str(home_elem: &ElementRef) -> Result<HomeAddress, Extra
 
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listing_structs::{ListingIndex, ObservationGranularity};
+    use crate::storage::test_frames::features;
+    use crate::storage::MemoryStore;
+
+    const ADDRESS: &str = r#"<span class="collapsedAddress primaryLine">12345 Main St, Crosby, TX 77532</span>"#;
+    const PRICE: &str = r#"<span class="homecardV2Price">$250,000</span>"#;
+
+    fn link(property_id: u64) -> String {
+        format!(r#"<a href="/TX/Crosby/12345-Main-St-77532/home/{}">home</a>"#, property_id)
+    }
+
+    /// A search page w/ a focused card and unfocused cards, each given as its inner html
+    fn search_page(focused: &str, unfocused: &[&str]) -> Html {
+        let unfocused: String = unfocused.iter()
+            .map(|card| format!(r#"<div class="HomeCardContainer defaultSplitMapListView">{}</div>"#, card))
+            .collect();
+        Html::parse_document(&format!(
+            r#"<html><body><div class="HomeCardContainer selectedHomeCard defaultSplitMapListView">{}</div>{}</body></html>"#,
+            focused, unfocused,
+        ))
+    }
+
+    /// First card on a page
+    fn first_card(page: &Html) -> ElementRef<'_> {
+        page.select(&Selector::parse("div.HomeCardContainer").unwrap()).next().unwrap()
+    }
+
+    /// Container w/ listings 1 and 2 known
+    fn known_listings() -> ListingsContainer<MemoryStore> {
+        let mut container = ListingsContainer::new(MemoryStore::default(), false, false, ObservationGranularity::default());
+        container.listing_features = features(&[(Some(1), 77532), (Some(2), 77532)]);
+        container.listing_index = ListingIndex::from_features(&container.listing_features);
+        container
+    }
+
+    fn source() -> CardSource {
+        CardSource { url: String::from("https://www.redfin.com/zipcode/77532"), page: 1, position: 0 }
+    }
+
+    #[test]
+    fn existing_card_w_missing_fields_is_an_error() {
+        let mut container = known_listings();
+        let page = search_page(&link(1), &[]);
+        assert!(matches!(container.update_existing_redfin(&first_card(&page), source()), Err(ExtractionError::Address(_))));
+        let page = search_page(&format!("{}{}", link(1), ADDRESS), &[]);
+        assert!(matches!(container.update_existing_redfin(&first_card(&page), source()), Err(ExtractionError::Price(_))));
+        assert_eq!(container.new_observations.height(), 0);
+
+        let page = search_page(&format!("{}{}{}", link(1), ADDRESS, PRICE), &[]);
+        container.update_existing_redfin(&first_card(&page), source()).unwrap();
+        assert_eq!(container.new_observations.column("price").unwrap().u32().unwrap().get(0), Some(250_000));
+    }
+
+    #[test]
+    fn failed_existing_cards_counted() {
+        let mut container = known_listings();
+        let page = search_page(&link(1), &[&format!("{}{}{}", link(2), ADDRESS, PRICE)]);
+        container.parse_redfin_page(&page, "https://www.redfin.com/zipcode/77532", 1);
+        assert_eq!(container.run.listings_failed, 1);
+        assert_eq!(container.run.listings_updated, 1);
+        assert_eq!(container.new_observations.height(), 1);
+    }
+
+    #[test]
+    fn page_count_reads_whole_number() {
+        assert_eq!(parse_page_count("Viewing page 1 of 12"), Some(12));
+        assert_eq!(parse_page_count("Viewing page 1 of 10"), Some(10));
+        assert_eq!(parse_page_count("Viewing page 1 of 3"), Some(3));
+    }
+
+    #[test]
+    fn page_count_unparsable() {
+        assert_eq!(parse_page_count("Viewing page 1"), None);
+        assert_eq!(parse_page_count("Viewing page 1 of many"), None);
+        let html = Html::parse_document("<div>No results</div>");
+        assert_eq!(get_redfin_page_count(&html), None);
+    }
+
+    /// Property link of a card w/ the given inner html
+    fn property_link(card: &str) -> Result<(u64, String), ExtractionError> {
+        extract_redfin_property_link(&first_card(&search_page(card, &[])))
+    }
+
+    #[test]
+    fn property_links() {
+        assert_eq!(
+            property_link(r#"<a href="/TX/Crosby/1-Main-St-77532/home/12345678">1 Main St</a>"#).unwrap(),
+            (12345678, String::from("https://www.redfin.com/TX/Crosby/1-Main-St-77532/home/12345678")),
+        );
+        assert_eq!(property_link(r#"<a href="/TX/Houston/100-Main-St-77002/unit-12/home/12345679?utm=1">unit</a>"#).unwrap().0, 12345679);
+        assert_eq!(property_link(r#"<a href="https://www.redfin.com/TX/Crosby/1-Main-St-77532/home/5">abs</a>"#).unwrap().1, "https://www.redfin.com/TX/Crosby/1-Main-St-77532/home/5");
+
+        assert!(matches!(property_link(r#"<a href="/TX/Crosby/1-Main-St-77532/home/">no id</a>"#), Err(ExtractionError::PropertyId(_))));
+        assert!(matches!(property_link(r#"<a href="/TX/Crosby">city</a>"#), Err(ExtractionError::PropertyId(_))));
+    }
+
+    /// Coordinates of a card holding the given JSON-LD scripts
+    fn coordinates(scripts: &[&str]) -> Option<(f64, f64)> {
+        let card: String = scripts.iter().map(|script| format!(r#"<script type="application/ld+json">{}</script>"#, script)).collect();
+        extract_redfin_coordinates(&first_card(&search_page(&card, &[])))
+    }
+
+    #[test]
+    fn json_ld_coordinates() {
+        let residence = r#"{"@type": "SingleFamilyResidence", "address": {"streetAddress": "1 Main St"}, "geo": {"@type": "GeoCoordinates", "latitude": 29.91, "longitude": -95.06}}"#;
+        assert_eq!(coordinates(&[residence]), Some((29.91, -95.06)));
+        // Nested, in a list, as strings
+        let nested = r#"[{"@type": "Product"}, {"@type": "Offer", "itemOffered": {"geo": {"latitude": "29.5", "longitude": "-95.5"}}}]"#;
+        assert_eq!(coordinates(&[nested]), Some((29.5, -95.5)));
+
+        assert_eq!(coordinates(&[r#"{"@type": "Product", "name": "1 Main St"}"#]), None);
+        assert_eq!(coordinates(&[r#"{"geo": {"latitude": 29.91}}"#]), None);
+        assert_eq!(coordinates(&[]), None);
+        // Malformed scripts are skipped, later ones still read
+        assert_eq!(coordinates(&[r#"{"geo": {"latitude": 29.91,"#, residence]), Some((29.91, -95.06)));
+        assert_eq!(coordinates(&["not json"]), None);
+    }
+
+    fn banner_status(banner: &str) -> ListingStatus {
+        let page = Html::parse_document(&format!(r#"<html><body><div class="ListingStatusBannerSection"><span>{}</span></div></body></html>"#, banner));
+        listing_page_status(&page)
+    }
+
+    #[test]
+    fn listing_page_banners() {
+        assert_eq!(banner_status("Sold on Jan 5, 2024"), ListingStatus::Sold);
+        assert_eq!(banner_status("Pending"), ListingStatus::Pending);
+        assert_eq!(banner_status("Contingent"), ListingStatus::Pending);
+        assert_eq!(banner_status("Under contract"), ListingStatus::Pending);
+        assert_eq!(banner_status("Active"), ListingStatus::Delisted);
+        assert_eq!(listing_page_status(&Html::parse_document("<html><body></body></html>")), ListingStatus::Delisted);
+    }
+
+    #[test]
+    fn reconciled_only_after_complete_scrape() {
+        assert!(zip_scrape_complete(77532, Some(3), 0, false));
+        assert!(!zip_scrape_complete(77532, Some(3), 1, false));
+        assert!(!zip_scrape_complete(77532, None, 0, false));
+        assert!(!zip_scrape_complete(77532, Some(3), 0, true));
+    }
+
+    #[tokio::test]
+    async fn reconcile_marks_unseen_listings() {
+        // Rows w/o a url are marked delisted w/o fetching their page
+        let mut container = known_listings();
+        let mut listings = features(&[(Some(1), 77532), (Some(2), 77532), (Some(3), 77532), (Some(4), 77002)]);
+        listings.with_column(Series::new("status", [None, None, Some("sold"), None])).unwrap();
+        container.listing_features = listings;
+        container.seen_this_run.extend([1, 3]);
+
+        container.reconcile_zip(77532).await;
+        let statuses = container.listing_features.column("status").unwrap().utf8().unwrap().clone();
+        let statuses: Vec<Option<&str>> = statuses.into_iter().collect();
+        assert_eq!(statuses, vec![None, Some("delisted"), Some("relisted"), None]);
+        assert_eq!(container.listing_features.column("status_date").unwrap().null_count(), 2);
+    }
+
+    #[test]
+    fn lot_size_units() {
+        assert_eq!(parse_redfin_lot_size("7,405 sq ft lot").unwrap(), (7405, LotUnit::SquareFeet));
+        assert_eq!(parse_redfin_lot_size("1.5 ac").unwrap(), (65340, LotUnit::Acres));
+        assert_eq!(parse_redfin_lot_size("0.26 acres").unwrap(), (11326, LotUnit::Acres));
+        assert_eq!(parse_redfin_lot_size("1 Acre Lot").unwrap(), (43560, LotUnit::Acres));
+    }
+
+    #[test]
+    fn lot_size_unknown() {
+        assert_eq!(parse_redfin_lot_size("— sq ft lot").unwrap(), (-1, LotUnit::SquareFeet));
+        assert!(matches!(parse_redfin_lot_size("big lot"), Err(ExtractionError::LotSize(_))));
+        assert!(matches!(parse_redfin_lot_size("many acres"), Err(ExtractionError::LotSize(_))));
+    }
+
+    #[test]
+    fn lot_stats() {
+        assert!(is_lot_stat("0.26 acres"));
+        assert!(is_lot_stat("1.5 ac"));
+        assert!(is_lot_stat("7,405 sq ft lot"));
+        assert!(!is_lot_stat("1,850 sq ft"));
+        assert!(!is_lot_stat("3 beds"));
+    }
+}
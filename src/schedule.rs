@@ -0,0 +1,229 @@
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, Datelike, Duration, TimeZone, Timelike, Utc};
+use serde::Deserialize;
+use std::str::FromStr;
+
+
+/// How far ahead `next_after` looks before deciding a schedule never fires, e.g. `0 0 30 2 *`
+const MAX_LOOKAHEAD_DAYS: i64 = 366 * 5;
+
+/// A five field cron expression (minute hour day-of-month month day-of-week), evaluated in UTC
+///
+/// Fields take `*`, numbers, ranges (`1-5`), steps (`*/15`, `0-30/10`) and comma separated lists.
+/// Day of week runs 0-7, both 0 and 7 are Sunday. As in cron, when both day fields are
+/// restricted a day matching either one fires
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) struct CronSchedule {
+    expression: String,
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days_of_month: Vec<bool>,
+    months: Vec<bool>,
+    days_of_week: Vec<bool>,
+    any_day_of_month: bool,
+    any_day_of_week: bool,
+}
+
+impl FromStr for CronSchedule {
+    type Err = anyhow::Error;
+
+    fn from_str(expression: &str) -> Result<Self> {
+        // Cause kept in the message, serde only shows the outermost error
+        CronSchedule::parse(expression).map_err(|e| anyhow!("Invalid cron schedule {:?}: {:#}", expression, e))
+    }
+}
+
+impl TryFrom<String> for CronSchedule {
+    type Error = anyhow::Error;
+
+    fn try_from(expression: String) -> Result<Self> {
+        expression.parse()
+    }
+}
+
+impl std::fmt::Display for CronSchedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.expression)
+    }
+}
+
+/// Which of `min..=max` a field matches, indexed by value
+fn parse_field(field: &str, min: u32, max: u32) -> Result<Vec<bool>> {
+    let mut matches = vec![false; max as usize + 1];
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| anyhow!("invalid step {:?}", step))?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            bail!("step of 0 in {:?}", part);
+        }
+        let (start, end) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((start, end)) => (parse_value(start, min, max)?, parse_value(end, min, max)?),
+                // `5/15` starts at 5 and runs to the end of the range
+                None if part.contains('/') => (parse_value(range, min, max)?, max),
+                None => {
+                    let value = parse_value(range, min, max)?;
+                    (value, value)
+                }
+            },
+        };
+        if start > end {
+            bail!("range {:?} runs backwards", range);
+        }
+        for value in (start..=end).step_by(step as usize) {
+            matches[value as usize] = true;
+        }
+    }
+    Ok(matches)
+}
+
+fn parse_value(value: &str, min: u32, max: u32) -> Result<u32> {
+    let parsed: u32 = value.parse().map_err(|_| anyhow!("invalid value {:?}", value))?;
+    if parsed < min || parsed > max {
+        bail!("{} outside {}-{}", parsed, min, max);
+    }
+    Ok(parsed)
+}
+
+impl CronSchedule {
+    fn parse(expression: &str) -> Result<Self> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minutes, hours, days_of_month, months, days_of_week] = fields[..] else {
+            bail!("needs 5 fields, has {}", fields.len());
+        };
+
+        let (any_day_of_month, any_day_of_week) = (days_of_month == "*", days_of_week == "*");
+        let mut days_of_week = parse_field(days_of_week, 0, 7).context("day of week")?;
+        // Sunday is both 0 and 7
+        if days_of_week[7] {
+            days_of_week[0] = true;
+        }
+        days_of_week.truncate(7);
+
+        Ok(CronSchedule {
+            expression: expression.to_string(),
+            minutes: parse_field(minutes, 0, 59).context("minute")?,
+            hours: parse_field(hours, 0, 23).context("hour")?,
+            days_of_month: parse_field(days_of_month, 1, 31).context("day of month")?,
+            months: parse_field(months, 1, 12).context("month")?,
+            days_of_week,
+            any_day_of_month,
+            any_day_of_week,
+        })
+    }
+
+    fn day_matches(&self, date: DateTime<Utc>) -> bool {
+        let day_of_month = self.days_of_month[date.day() as usize];
+        let day_of_week = self.days_of_week[date.weekday().num_days_from_sunday() as usize];
+        match (self.any_day_of_month, self.any_day_of_week) {
+            (false, false) => day_of_month || day_of_week,
+            _ => day_of_month && day_of_week,
+        }
+    }
+
+    /// First minute strictly after `after` the schedule fires at, None if it never does
+    pub(crate) fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let start = after.with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        let limit = start + Duration::days(MAX_LOOKAHEAD_DAYS);
+
+        // Skips whole months, days and hours that can't match before checking minutes
+        let mut next = start;
+        while next < limit {
+            if !self.months[next.month() as usize] {
+                let (year, month) = match next.month() {
+                    12 => (next.year() + 1, 1),
+                    month => (next.year(), month + 1),
+                };
+                next = Utc.with_ymd_and_hms(year, month, 1, 0, 0, 0).single()?;
+            } else if !self.day_matches(next) {
+                next = Utc.with_ymd_and_hms(next.year(), next.month(), next.day(), 0, 0, 0).single()? + Duration::days(1);
+            } else if !self.hours[next.hour() as usize] {
+                next = next.with_minute(0)? + Duration::hours(1);
+            } else if !self.minutes[next.minute() as usize] {
+                next += Duration::minutes(1);
+            } else {
+                return Some(next);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, month, day, hour, minute, 0).unwrap()
+    }
+
+    fn next(expression: &str, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        expression.parse::<CronSchedule>().unwrap().next_after(after)
+    }
+
+    #[test]
+    fn minute_steps() {
+        assert_eq!(next("*/15 * * * *", at(1, 1, 10, 7)), Some(at(1, 1, 10, 15)));
+        assert_eq!(next("*/15 * * * *", at(1, 1, 10, 45)), Some(at(1, 1, 11, 0)));
+        assert_eq!(next("5/20 * * * *", at(1, 1, 10, 30)), Some(at(1, 1, 10, 45)));
+        assert_eq!(next("0-30/10 * * * *", at(1, 1, 10, 31)), Some(at(1, 1, 11, 0)));
+    }
+
+    #[test]
+    fn strictly_after() {
+        assert_eq!(next("30 9 * * *", at(1, 1, 9, 30)), Some(at(1, 2, 9, 30)));
+        let mid_minute = at(1, 1, 9, 29) + Duration::seconds(59);
+        assert_eq!(next("30 9 * * *", mid_minute), Some(at(1, 1, 9, 30)));
+    }
+
+    #[test]
+    fn weekdays_skip_weekend() {
+        // 2024-01-05 is a Friday
+        assert_eq!(next("0 9 * * 1-5", at(1, 5, 9, 0)), Some(at(1, 8, 9, 0)));
+        assert_eq!(next("0 9 * * 1-5", at(1, 5, 8, 0)), Some(at(1, 5, 9, 0)));
+        // Sunday is 0 or 7
+        assert_eq!(next("0 9 * * 7", at(1, 5, 9, 0)), Some(at(1, 7, 9, 0)));
+        assert_eq!(next("0 9 * * 0", at(1, 5, 9, 0)), Some(at(1, 7, 9, 0)));
+    }
+
+    #[test]
+    fn restricted_day_fields_either_match() {
+        // The 15th or a Monday, 2024-01-08 is the first Monday after the 3rd
+        assert_eq!(next("0 0 15 * 1", at(1, 3, 0, 0)), Some(at(1, 8, 0, 0)));
+        assert_eq!(next("0 0 15 * 1", at(1, 12, 0, 0)), Some(at(1, 15, 0, 0)));
+        assert_eq!(next("0 0 10 * 1", at(1, 8, 0, 0)), Some(at(1, 10, 0, 0)));
+        // Only one restricted, both must match
+        assert_eq!(next("0 0 15 * *", at(1, 3, 0, 0)), Some(at(1, 15, 0, 0)));
+    }
+
+    #[test]
+    fn months_and_years_roll_over() {
+        assert_eq!(next("0 0 1 * *", at(12, 15, 0, 0)), Some(Utc.with_ymd_and_hms(2025, 1, 1, 0, 0, 0).unwrap()));
+        assert_eq!(next("0 12 * 3,6 *", at(1, 20, 0, 0)), Some(at(3, 1, 12, 0)));
+        assert_eq!(next("0 0 29 2 *", at(3, 1, 0, 0)), Some(Utc.with_ymd_and_hms(2028, 2, 29, 0, 0, 0).unwrap()));
+    }
+
+    #[test]
+    fn never_fires() {
+        assert_eq!(next("0 0 30 2 *", at(1, 1, 0, 0)), None);
+        assert_eq!(next("0 0 31 4,6,9,11 *", at(1, 1, 0, 0)), None);
+    }
+
+    #[test]
+    fn parse_errors() {
+        for expression in ["* * * *", "* * * * * *", "60 * * * *", "* 24 * * *", "* * 0 * *", "* * * 13 *", "* * * * 8", "*/0 * * * *", "30-10 * * * *", "a * * * *", "*/x * * * *"] {
+            assert!(expression.parse::<CronSchedule>().is_err(), "{}", expression);
+        }
+        let e = "0 9 * * 1-9".parse::<CronSchedule>().unwrap_err();
+        assert!(format!("{:#}", e).contains("day of week"));
+    }
+
+    #[test]
+    fn displays_expression() {
+        assert_eq!("0 9 * * 1-5".parse::<CronSchedule>().unwrap().to_string(), "0 9 * * 1-5");
+    }
+}
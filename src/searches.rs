@@ -0,0 +1,380 @@
+use polars::prelude::*;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use serde::Deserialize;
+use tracing::{info, warn};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use crate::listing_structs::ListingsContainer;
+use crate::schedule::CronSchedule;
+use crate::storage::{conform_to_schema, Dataset, ListingStore};
+
+
+/// Saved searches, read from the file given as `searches=<path>`
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct SearchConfig {
+    pub(crate) searches: Vec<SavedSearch>,
+}
+
+impl SearchConfig {
+    /// Names must be unique and comma free, they're stored as comma separated lists
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        let config = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read search config {}", path.display()))?;
+        let config: SearchConfig = serde_json::from_str(&config)
+            .with_context(|| format!("Invalid search config {}", path.display()))?;
+
+        let mut names = HashSet::new();
+        for search in config.searches.iter() {
+            if search.name.is_empty() || search.name.contains(',') {
+                bail!("Invalid saved search name {:?}, must be non empty w/o commas", search.name);
+            }
+            if !names.insert(search.name.as_str()) {
+                bail!("Duplicate saved search name {:?}", search.name);
+            }
+            if search.search_type != "zipcode" {
+                bail!("Saved search {:?}: unsupported search type {:?}, only zipcode", search.name, search.search_type);
+            }
+            search.zip()?;
+        }
+        Ok(config)
+    }
+}
+
+/// A named search the scraper runs on its own schedule
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct SavedSearch {
+    pub(crate) name: String,
+    /// Only `zipcode` is supported
+    pub(crate) search_type: String,
+    pub(crate) target: String,
+    #[serde(default)]
+    pub(crate) filters: SearchFilters,
+    /// Minimum time between runs of the search, 0 runs it every time
+    #[serde(default)]
+    pub(crate) interval_hours: u32,
+    /// When daemon mode runs the search, every `interval_hours` if unset
+    pub(crate) schedule: Option<CronSchedule>,
+    /// Daemon mode delays each run by up to this many seconds
+    #[serde(default)]
+    pub(crate) jitter_secs: u64,
+}
+
+impl SavedSearch {
+    pub(crate) fn zip(&self) -> Result<u32> {
+        self.target.parse()
+            .with_context(|| format!("Saved search {:?}: invalid zipcode {:?}", self.name, self.target))
+    }
+
+    fn is_due(&self, last_run: Option<DateTime<Utc>>, now: DateTime<Utc>) -> bool {
+        last_run.is_none_or(|last_run| now - last_run >= Duration::hours(self.interval_hours as i64))
+    }
+
+    /// Next time daemon mode runs the search after `after`, w/o jitter
+    ///
+    /// None if it has neither a schedule nor an interval, or the schedule never fires
+    pub(crate) fn next_run(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match (self.schedule.as_ref(), self.interval_hours) {
+            (Some(schedule), _) => schedule.next_after(after),
+            (None, 0) => None,
+            (None, hours) => Some(after + Duration::hours(hours as i64)),
+        }
+    }
+}
+
+/// Conditions a listing found by the search must meet to be attributed to it, unset ones always hold
+#[derive(Debug, Clone, Default, Deserialize)]
+pub(crate) struct SearchFilters {
+    pub(crate) min_price: Option<u32>,
+    pub(crate) max_price: Option<u32>,
+    pub(crate) min_beds: Option<i32>,
+    pub(crate) min_baths: Option<i32>,
+    pub(crate) min_sqft: Option<u32>,
+}
+
+impl SearchFilters {
+    /// A listing's current price and features, missing values fail any filter set on them
+    fn matches(&self, price: Option<u32>, beds: Option<i32>, baths: Option<i32>, sqft: Option<u32>) -> bool {
+        let at_least = |min: Option<u32>, value: Option<u32>| min.is_none_or(|min| value.is_some_and(|value| value >= min));
+        at_least(self.min_price, price)
+            && self.max_price.is_none_or(|max| price.is_some_and(|price| price <= max))
+            && self.min_beds.is_none_or(|min| beds.is_some_and(|beds| beds >= min))
+            && self.min_baths.is_none_or(|min| baths.is_some_and(|baths| baths >= min))
+            && at_least(self.min_sqft, sqft)
+    }
+}
+
+/// Start of the last recorded run of each saved search
+pub(crate) fn last_runs(runs: Option<DataFrame>) -> Result<HashMap<String, DateTime<Utc>>> {
+    let mut last_runs: HashMap<String, DateTime<Utc>> = HashMap::new();
+    let Some(runs) = runs else { return Ok(last_runs) };
+    let (runs, _) = conform_to_schema(&runs, &Dataset::Runs.schema())?;
+
+    let started = runs.column("started_at")?.cast(&DataType::Int64)?;
+    let (started, names) = (started.i64()?, runs.column("saved_searches")?.utf8()?);
+    for (started, names) in started.into_iter().zip(names) {
+        let (Some(started), Some(names)) = (started, names) else { continue };
+        let Some(started) = Utc.timestamp_millis_opt(started).single() else { continue };
+        for name in names.split(',') {
+            let last_run = last_runs.entry(name.to_string()).or_insert(started);
+            *last_run = started.max(*last_run);
+        }
+    }
+    Ok(last_runs)
+}
+
+/// Ids of the listings in `saved_searches` that list `name`
+pub(crate) fn watch_list(features: &DataFrame, name: &str) -> PolarsResult<Vec<u64>> {
+    let (ids, searches) = (features.column("property_id")?.u64()?, features.column("saved_searches")?.utf8()?);
+    Ok(ids.into_iter().zip(searches)
+        .filter_map(|(id, searches)| id.filter(|_| searches.is_some_and(|searches| searches.split(',').any(|search| search == name))))
+        .collect())
+}
+
+impl<S: ListingStore> ListingsContainer<S> {
+    /// Runs every due saved search, each zip is scraped once however many searches target it
+    ///
+    /// A search is due if it never ran or its last run started at least `interval_hours` ago
+    pub(crate) async fn run_saved_searches(&mut self) {
+        let now = Utc::now();
+        let last_runs = self.saved_search_last_runs();
+        let due: Vec<SavedSearch> = self.saved_searches.iter()
+            .filter(|search| {
+                let is_due = search.is_due(last_runs.get(&search.name).copied(), now);
+                if !is_due {
+                    info!("Saved search {} not due", search.name);
+                }
+                is_due
+            })
+            .cloned()
+            .collect();
+        self.run_searches(&due).await;
+    }
+
+    /// Start of each saved search's last run, from the stored runs
+    pub(crate) fn saved_search_last_runs(&self) -> HashMap<String, DateTime<Utc>> {
+        self.store.read(Dataset::Runs)
+            .and_then(last_runs)
+            .unwrap_or_else(|e| {
+                warn!("Failed to read past runs, treating every saved search as never run: {:#}", e);
+                HashMap::new()
+            })
+    }
+
+    /// Runs the searches in this run and attributes their listings, stops early once shutdown is requested
+    pub(crate) async fn run_searches(&mut self, searches: &[SavedSearch]) {
+        let mut due_by_zip: BTreeMap<u32, Vec<&SavedSearch>> = BTreeMap::new();
+        for search in searches.iter() {
+            match search.zip() {
+                Ok(zip) => due_by_zip.entry(zip).or_default().push(search),
+                Err(e) => warn!("Skipping saved search: {:#}", e),
+            }
+        }
+
+        for (zip, searches) in due_by_zip {
+            if self.shutdown_requested() {
+                info!("Shutdown requested, skipping saved searches on zip {}", zip);
+                self.run.partial = true;
+                continue;
+            }
+            info!("Running saved searches {:?} on zip {}", searches.iter().map(|search| &search.name).collect::<Vec<_>>(), zip);
+            self.homes_by_zip(zip).await;
+            for search in searches {
+                self.attribute_search(search, zip);
+                self.run.saved_searches.push(search.name.clone());
+            }
+        }
+    }
+
+    /// Adds the search's name to every listing in its zip seen this run that passes its filters
+    ///
+    /// Listings are priced at their last recorded observation
+    fn attribute_search(&mut self, search: &SavedSearch, zip: u32) {
+        let features = &self.listing_features;
+        let columns = (
+            features.column("property_id").and_then(|col| col.u64()),
+            features.column("zip").and_then(|col| col.u32()),
+            features.column("beds").and_then(|col| col.i32()),
+            features.column("baths").and_then(|col| col.i32()),
+            features.column("sqft").and_then(|col| col.u32()),
+            features.column("saved_searches").and_then(|col| col.utf8()),
+        );
+        let (Ok(ids), Ok(zips), Ok(beds), Ok(baths), Ok(sqfts), Ok(saved_searches)) = columns else {
+            warn!("Unexpected features column types, saved search {} not attributed", search.name);
+            return;
+        };
+
+        let mut matched = 0;
+        let saved_searches: Utf8Chunked = (0..features.height())
+            .map(|row_idx| {
+                let current = saved_searches.get(row_idx);
+                let Some(id) = ids.get(row_idx) else { return current.map(String::from) };
+                let is_match = zips.get(row_idx) == Some(zip)
+                    && self.seen_this_run.contains(&id)
+                    && search.filters.matches(
+                        self.listing_index.last_observation(id).map(|(_, price)| price),
+                        beds.get(row_idx),
+                        baths.get(row_idx),
+                        sqfts.get(row_idx),
+                    );
+                if !is_match {
+                    return current.map(String::from);
+                }
+                matched += 1;
+                match current {
+                    Some(current) if current.split(',').any(|name| name == search.name) => Some(current.to_string()),
+                    Some(current) => Some(format!("{},{}", current, search.name)),
+                    None => Some(search.name.clone()),
+                }
+            })
+            .collect();
+
+        info!("Saved search {} matched {} listings", search.name, matched);
+        self.listing_features.with_column(saved_searches.with_name("saved_searches").into_series())
+            .expect("same height as features");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listing_structs::{ObservationGranularity, ScrapeRun};
+    use crate::storage::test_frames::{day, features};
+    use crate::storage::MemoryStore;
+
+    fn search(name: &str, target: &str) -> SavedSearch {
+        SavedSearch {
+            name: name.to_string(),
+            search_type: String::from("zipcode"),
+            target: target.to_string(),
+            filters: SearchFilters::default(),
+            interval_hours: 0,
+            schedule: None,
+            jitter_secs: 0,
+        }
+    }
+
+    #[test]
+    fn filter_bounds() {
+        assert!(SearchFilters::default().matches(None, None, None, None));
+
+        let price = SearchFilters { min_price: Some(100), max_price: Some(200), ..Default::default() };
+        assert!(price.matches(Some(100), None, None, None) && price.matches(Some(200), None, None, None));
+        assert!(!price.matches(Some(99), None, None, None) && !price.matches(Some(201), None, None, None));
+        assert!(!price.matches(None, Some(3), Some(2), Some(1500)));
+
+        let beds = SearchFilters { min_beds: Some(3), ..Default::default() };
+        assert!(beds.matches(None, Some(3), None, None));
+        assert!(!beds.matches(None, Some(2), None, None) && !beds.matches(None, None, None, None));
+
+        let baths = SearchFilters { min_baths: Some(2), ..Default::default() };
+        assert!(baths.matches(None, None, Some(2), None));
+        assert!(!baths.matches(None, None, Some(1), None) && !baths.matches(None, None, None, None));
+
+        let sqft = SearchFilters { min_sqft: Some(1500), ..Default::default() };
+        assert!(sqft.matches(None, None, None, Some(1500)));
+        assert!(!sqft.matches(None, None, None, Some(1499)) && !sqft.matches(None, None, None, None));
+    }
+
+    /// Loads a config holding the given searches as JSON
+    fn load(searches: &str) -> Result<SearchConfig> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("searches.json");
+        fs::write(&path, format!(r#"{{"searches": [{}]}}"#, searches)).unwrap();
+        SearchConfig::load(&path)
+    }
+
+    #[test]
+    fn load_validates_searches() {
+        let config = load(r#"
+            {"name": "cheap", "search_type": "zipcode", "target": "77532", "filters": {"max_price": 300000}, "schedule": "0 9 * * 1-5"},
+            {"name": "big", "search_type": "zipcode", "target": "77532", "interval_hours": 12}
+        "#).unwrap();
+        assert_eq!(config.searches.len(), 2);
+        assert_eq!(config.searches[0].filters.max_price, Some(300000));
+        assert_eq!(config.searches[0].schedule.as_ref().unwrap().to_string(), "0 9 * * 1-5");
+        assert_eq!(config.searches[1].interval_hours, 12);
+
+        let error = |searches: &str| format!("{:#}", load(searches).unwrap_err());
+        assert!(error(r#"{"name": "a", "search_type": "zipcode", "target": "77532"}, {"name": "a", "search_type": "zipcode", "target": "77002"}"#).contains("Duplicate"));
+        assert!(error(r#"{"name": "", "search_type": "zipcode", "target": "77532"}"#).contains("Invalid saved search name"));
+        assert!(error(r#"{"name": "a,b", "search_type": "zipcode", "target": "77532"}"#).contains("Invalid saved search name"));
+        assert!(error(r#"{"name": "a", "search_type": "zipcode", "target": ""}"#).contains("invalid zipcode"));
+        assert!(error(r#"{"name": "a", "search_type": "city", "target": "Crosby"}"#).contains("unsupported search type"));
+        assert!(error(r#"{"name": "a", "search_type": "zipcode", "target": "77532", "schedule": "0 25 * * *"}"#).contains("Invalid cron schedule"));
+    }
+
+    #[test]
+    fn due_after_interval() {
+        let mut every_day = search("daily", "77532");
+        every_day.interval_hours = 24;
+        assert!(every_day.is_due(None, day(2)));
+        assert!(every_day.is_due(Some(day(1)), day(2)));
+        assert!(!every_day.is_due(Some(day(1)), day(2) - Duration::minutes(1)));
+        assert!(search("always", "77532").is_due(Some(day(2)), day(2)));
+
+        assert_eq!(every_day.next_run(day(1)), Some(day(2)));
+        assert_eq!(search("always", "77532").next_run(day(1)), None);
+        every_day.schedule = Some("0 9 * * *".parse().unwrap());
+        assert_eq!(every_day.next_run(day(1)), Some(Utc.with_ymd_and_hms(2024, 1, 2, 9, 0, 0).unwrap()));
+    }
+
+    #[test]
+    fn last_run_of_each_search() {
+        assert!(last_runs(None).unwrap().is_empty());
+
+        let run = |started: DateTime<Utc>, names: &[&str]| {
+            let mut run = ScrapeRun::new(started);
+            run.saved_searches = names.iter().map(|name| name.to_string()).collect();
+            run.to_frame()
+        };
+        let mut runs = run(day(1), &["a", "b"]);
+        runs.vstack_mut(&run(day(3), &["a"])).unwrap();
+        runs.vstack_mut(&run(day(2), &["b"])).unwrap();
+        runs.vstack_mut(&run(day(4), &[])).unwrap();
+
+        let mut store = MemoryStore::default();
+        store.record_run(&runs).unwrap();
+        store.commit().unwrap();
+        let container = ListingsContainer::new(store, false, false, ObservationGranularity::default());
+        let last_runs = container.saved_search_last_runs();
+        assert_eq!(last_runs.len(), 2);
+        assert_eq!(last_runs["a"], day(3));
+        assert_eq!(last_runs["b"], day(2));
+
+        // Due against its own last run, not the last run of any search
+        let mut every_two_days = search("a", "77532");
+        every_two_days.interval_hours = 48;
+        assert!(!every_two_days.is_due(last_runs.get("a").copied(), day(4)));
+        every_two_days.name = String::from("b");
+        assert!(every_two_days.is_due(last_runs.get("b").copied(), day(4)));
+    }
+
+    #[test]
+    fn attributes_seen_listings_passing_filters() {
+        let mut container = ListingsContainer::new(MemoryStore::default(), false, false, ObservationGranularity::default());
+        // 1-3 in 77532, 4 in 77002. 3 isn't seen this run, 5 has no price recorded
+        let mut listings = features(&[(Some(1), 77532), (Some(2), 77532), (Some(3), 77532), (Some(4), 77002), (Some(5), 77532)]);
+        listings.with_column(Series::new("beds", [Some(3), Some(2), Some(3), Some(3), Some(3)])).unwrap();
+        container.listing_features = listings;
+        for (id, price) in [(1, 250_000), (2, 250_000), (3, 250_000), (4, 250_000)] {
+            container.listing_index.observe(id, day(1), price);
+        }
+        container.seen_this_run.extend([1, 2, 4, 5]);
+
+        let mut three_beds = search("three_beds", "77532");
+        three_beds.filters = SearchFilters { min_beds: Some(3), max_price: Some(300_000), ..Default::default() };
+        container.attribute_search(&three_beds, 77532);
+        // Attributing again doesn't repeat the name
+        container.attribute_search(&three_beds, 77532);
+        container.attribute_search(&search("any", "77532"), 77532);
+
+        assert_eq!(watch_list(&container.listing_features, "three_beds").unwrap(), vec![1]);
+        assert_eq!(watch_list(&container.listing_features, "any").unwrap(), vec![1, 2, 5]);
+        let saved_searches = container.listing_features.column("saved_searches").unwrap().utf8().unwrap().clone();
+        assert_eq!(saved_searches.get(0), Some("three_beds,any"));
+        assert_eq!(saved_searches.get(3), None);
+    }
+}
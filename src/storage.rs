@@ -0,0 +1,873 @@
+use polars::prelude::*;
+use anyhow::{anyhow, Context, Result};
+use tracing::{info, warn};
+use std::path::PathBuf;
+use std::str::FromStr;
+use chrono::{DateTime, Utc};
+
+mod files;
+mod memory;
+mod sqlite;
+pub(crate) use files::{FileFormat, FileStore, HistoryLayout};
+pub(crate) use memory::MemoryStore;
+pub(crate) use sqlite::SqliteStore;
+
+
+/// Datasets persisted between runs
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum Dataset {
+    Features,
+    History,
+    /// One row per scrape run, for provenance and auditing
+    Runs,
+    /// One row per listing price change, derived from history for the listings each save observed
+    PriceEvents,
+}
+
+impl Dataset {
+    /// File name w/o extension, the only place dataset names are defined
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Dataset::Features => "listing_features",
+            Dataset::History => "listing_history",
+            Dataset::Runs => "scrape_runs",
+            Dataset::PriceEvents => "price_events",
+        }
+    }
+
+    pub(crate) fn schema(&self) -> Schema {
+        match self {
+            Dataset::Features => feature_schema(),
+            Dataset::History => history_schema(),
+            Dataset::Runs => runs_schema(),
+            Dataset::PriceEvents => price_events_schema(),
+        }
+    }
+}
+
+/// Version of the dataset schemas written by this build
+///
+/// Bump it w/ a new entry in `MIGRATIONS` whenever a schema changes
+pub(crate) const SCHEMA_VERSION: u32 = 10;
+
+/// Upgrade from the previous version, each step gets the other dataset as stored
+struct Migration {
+    /// Version this step upgrades to
+    version: u32,
+    description: &'static str,
+    /// (raw features, stored history) -> features
+    features: fn(DataFrame, &DataFrame) -> PolarsResult<DataFrame>,
+    /// (raw history, migrated features) -> history
+    history: fn(DataFrame, &DataFrame) -> PolarsResult<DataFrame>,
+}
+
+/// Columns added by a step w/o a function are filled w/ nulls when cast to the schema
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 2,
+        description: "lot unit and coordinates added to features",
+        features: |df, _| Ok(df),
+        history: |df, _| Ok(df),
+    },
+    Migration {
+        version: 3,
+        description: "listings keyed on redfin property id",
+        features: |df, _| Ok(df),
+        history: migrate_legacy_history,
+    },
+    Migration {
+        version: 4,
+        description: "first/last seen span added to features",
+        features: backfill_seen_span,
+        history: |df, _| Ok(df),
+    },
+    Migration {
+        version: 5,
+        description: "times stored as UTC datetimes instead of u32 unix seconds",
+        features: |df, _| unix_seconds_to_timestamps(df, &["first_seen", "last_seen"]),
+        history: |df, _| unix_seconds_to_timestamps(df, &["date"]),
+    },
+    Migration {
+        version: 6,
+        description: "scrape runs recorded, observations reference their run",
+        features: |df, _| Ok(df),
+        history: |df, _| Ok(df),
+    },
+    Migration {
+        version: 7,
+        description: "observations record the search page and card they came from",
+        features: |df, _| Ok(df),
+        history: |df, _| Ok(df),
+    },
+    Migration {
+        version: 8,
+        description: "listing status added to features, scrape runs count failed pages",
+        features: |df, _| backfill_status(df),
+        history: |df, _| Ok(df),
+    },
+    Migration {
+        version: 9,
+        description: "listings and runs record their saved searches",
+        features: |df, _| Ok(df),
+        history: |df, _| Ok(df),
+    },
+    Migration {
+        version: 10,
+        description: "runs record whether they were stopped early",
+        features: |df, _| Ok(df),
+        history: |df, _| Ok(df),
+    },
+];
+
+/// Guesses the version of a store written before versions were recorded, from its features columns
+///
+/// Markers were added at v4, every later store has one
+fn infer_version(features: &DataFrame) -> u32 {
+    let has = |col_name: &str| features.column(col_name).is_ok();
+    match (has("latitude"), has("property_id"), has("first_seen")) {
+        (_, _, true) => 4,
+        (_, true, _) => 3,
+        (true, _, _) => 2,
+        _ => 1,
+    }
+}
+
+/// Runs every migration step after `from_version`
+fn migrate_from(
+    from_version: u32,
+    df: DataFrame,
+    step: impl Fn(&Migration, DataFrame) -> PolarsResult<DataFrame>,
+) -> PolarsResult<DataFrame> {
+    MIGRATIONS.iter()
+        .filter(|migration| migration.version > from_version)
+        .try_fold(df, |df, migration| {
+            info!("Migrating to v{}: {}", migration.version, migration.description);
+            step(migration, df)
+        })
+}
+
+/// Observation times: UTC, millisecond precision
+pub(crate) fn timestamp_dtype() -> DataType {
+    DataType::Datetime(TimeUnit::Milliseconds, Some("UTC".to_string()))
+}
+
+/// Timestamp column from chrono datetimes
+pub(crate) fn timestamp_series(name: &str, times: &[DateTime<Utc>]) -> Series {
+    let millis: Vec<i64> = times.iter().map(|time| time.timestamp_millis()).collect();
+    Int64Chunked::from_vec(name, millis)
+        .into_datetime(TimeUnit::Milliseconds, Some("UTC".to_string()))
+        .into_series()
+}
+
+pub(crate) fn feature_schema() -> Schema {
+    Schema::from_iter(
+        vec![
+            Field::new("property_id", DataType::UInt64),
+            Field::new("url", DataType::Utf8),
+            Field::new("beds", DataType::Int32),
+            Field::new("baths", DataType::Int32),
+            Field::new("sqft", DataType::UInt32),
+            Field::new("lot_size", DataType::Int32),
+            Field::new("lot_unit", DataType::Utf8),
+            Field::new("street", DataType::Utf8),
+            Field::new("apt", DataType::Int32),
+            Field::new("city", DataType::Utf8),
+            Field::new("state", DataType::Utf8),
+            Field::new("zip", DataType::UInt32),
+            Field::new("addr_str", DataType::Utf8),
+            Field::new("latitude", DataType::Float64),
+            Field::new("longitude", DataType::Float64),
+            Field::new("first_seen", timestamp_dtype()),
+            Field::new("last_seen", timestamp_dtype()),
+            // A `ListingStatus`, set w/ status_date when a reconciliation changes it
+            Field::new("status", DataType::Utf8),
+            Field::new("status_date", timestamp_dtype()),
+            // Comma separated names of the saved searches that matched the listing
+            Field::new("saved_searches", DataType::Utf8),
+        ]
+    )
+}
+
+pub(crate) fn history_schema() -> Schema {
+    Schema::from_iter(
+        vec![
+            Field::new("property_id", DataType::UInt64),
+            Field::new("addr_str", DataType::Utf8),
+            Field::new("date", timestamp_dtype()),
+            Field::new("price", DataType::UInt32),
+            // Null for rows recorded before runs were tracked
+            Field::new("run_id", DataType::UInt64),
+            // Search page and card the price was read from, null before v7
+            Field::new("source_url", DataType::Utf8),
+            Field::new("page", DataType::UInt32),
+            Field::new("card_position", DataType::UInt32),
+        ]
+    )
+}
+
+pub(crate) fn runs_schema() -> Schema {
+    Schema::from_iter(
+        vec![
+            Field::new("run_id", DataType::UInt64),
+            Field::new("started_at", timestamp_dtype()),
+            Field::new("finished_at", timestamp_dtype()),
+            // Stopped early by Ctrl-C or SIGTERM, null for runs recorded before v10
+            Field::new("partial", DataType::Boolean),
+            Field::new("search_type", DataType::Utf8),
+            Field::new("search_target", DataType::Utf8),
+            // Comma separated names, null for runs w/o saved searches
+            Field::new("saved_searches", DataType::Utf8),
+            Field::new("pages_fetched", DataType::UInt32),
+            Field::new("pages_failed", DataType::UInt32),
+            Field::new("listings_seen", DataType::UInt32),
+            Field::new("listings_new", DataType::UInt32),
+            Field::new("listings_updated", DataType::UInt32),
+            Field::new("listings_failed", DataType::UInt32),
+            Field::new("fetcher", DataType::Utf8),
+            Field::new("crate_version", DataType::Utf8),
+        ]
+    )
+}
+
+pub(crate) fn price_events_schema() -> Schema {
+    Schema::from_iter(
+        vec![
+            Field::new("property_id", DataType::UInt64),
+            Field::new("addr_str", DataType::Utf8),
+            Field::new("date", timestamp_dtype()),
+            Field::new("old_price", DataType::UInt32),
+            Field::new("new_price", DataType::UInt32),
+            Field::new("price_delta", DataType::Int64),
+            Field::new("percent_delta", DataType::Float64),
+            // Since the listing's previous change, or its first observation for the first change
+            Field::new("days_since_change", DataType::Float64),
+            // First price recorded for the listing
+            Field::new("list_price", DataType::UInt32),
+            // Positive when below the list price
+            Field::new("cumulative_drop", DataType::Int64),
+            Field::new("cumulative_drop_percent", DataType::Float64),
+        ]
+    )
+}
+
+/// No rows, all schema columns
+pub(crate) fn empty_frame(schema: &Schema) -> DataFrame {
+    let cols: Vec<Series> = schema
+        .iter()
+        .map(|(col_name, dtype)| Series::new_empty(col_name, dtype))
+        .collect();
+    DataFrame::new(cols).expect("schema columns are unique")
+}
+
+/// Narrows a history scan to some zips and/or a date range
+///
+/// Stores w/ partitioned history skip partitions that can't match, rows are always filtered
+#[derive(Debug, Clone, Default)]
+pub(crate) struct HistoryFilter {
+    /// Only listings in these zips
+    pub(crate) zips: Option<Vec<u32>>,
+    /// Only observations at or after this time
+    pub(crate) since: Option<DateTime<Utc>>,
+    /// Only observations at or before this time
+    pub(crate) until: Option<DateTime<Utc>>,
+}
+
+impl HistoryFilter {
+    pub(crate) fn zip(zip: u32) -> Self {
+        HistoryFilter { zips: Some(vec![zip]), ..Default::default() }
+    }
+
+    /// Whether a partition could hold matching rows, None values are unknown and always match
+    ///
+    /// `day` is days since the unix epoch (UTC)
+    pub(crate) fn may_match(&self, zip: Option<u32>, day: Option<u32>) -> bool {
+        let zip_matches = match (&self.zips, zip) {
+            (Some(zips), Some(zip)) => zips.contains(&zip),
+            _ => true,
+        };
+        let days_since_epoch = |time: DateTime<Utc>| time.timestamp().div_euclid(SECS_PER_DAY);
+        let day_matches = match day.map(i64::from) {
+            Some(day) => self.since.is_none_or(|since| day >= days_since_epoch(since))
+                && self.until.is_none_or(|until| day <= days_since_epoch(until)),
+            None => true,
+        };
+        zip_matches && day_matches
+    }
+
+    /// Adds the row filters to a history scan
+    ///
+    /// History rows don't carry a zip, zip filters keep ids whose features row is in one of the zips
+    pub(crate) fn apply<S: ListingStore + ?Sized>(&self, history: LazyFrame, store: &S) -> Result<LazyFrame> {
+        let mut history = history;
+        // Compared as epoch millis, literals carry no timezone
+        if let Some(since) = self.since {
+            history = history.filter(col("date").cast(DataType::Int64).gt_eq(lit(since.timestamp_millis())));
+        }
+        if let Some(until) = self.until {
+            history = history.filter(col("date").cast(DataType::Int64).lt_eq(lit(until.timestamp_millis())));
+        }
+        if let Some(zips) = self.zips.as_ref() {
+            let ids = match store.scan(Dataset::Features)? {
+                Some(features) => features
+                    .filter(col("zip").is_in(lit(Series::new("zips", zips.clone()))))
+                    .select([col("property_id")])
+                    .collect()?
+                    .column("property_id")?
+                    .clone(),
+                None => Series::new_empty("property_id", &DataType::UInt64),
+            };
+            history = history.filter(col("property_id").is_in(lit(ids)));
+        }
+        Ok(history)
+    }
+}
+
+const SECS_PER_DAY: i64 = 86_400;
+
+/// How a dataset was initialized at startup
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum LoadOutcome {
+    /// Read as-is
+    Loaded,
+    /// Read, upgraded from an older schema version, columns it lacked were added as nulls
+    Migrated { from_version: u32, added: Vec<String> },
+    /// Current version was unreadable, the most recent good backup generation was used instead
+    Restored { generation: usize, reason: String },
+    /// Started from an empty frame
+    Empty(EmptyReason),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum EmptyReason {
+    ForceRefresh,
+    NotFound,
+    /// File exists but couldn't be read or cast to the schema
+    Rejected(String),
+}
+
+impl std::fmt::Display for LoadOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadOutcome::Loaded => write!(f, "loaded"),
+            LoadOutcome::Migrated { from_version, added } if added.is_empty() => write!(f, "migrated from v{}", from_version),
+            LoadOutcome::Migrated { from_version, added } => write!(f, "migrated from v{} (added {})", from_version, added.join(", ")),
+            LoadOutcome::Restored { generation, reason } => write!(f, "restored from backup {} (current rejected: {})", generation, reason),
+            LoadOutcome::Empty(EmptyReason::ForceRefresh) => write!(f, "empty (force refresh)"),
+            LoadOutcome::Empty(EmptyReason::NotFound) => write!(f, "empty (no local data)"),
+            LoadOutcome::Empty(EmptyReason::Rejected(reason)) => write!(f, "empty (rejected: {})", reason),
+        }
+    }
+}
+
+/// Where the datasets are persisted, selected w/ the `storage=` arg
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub(crate) enum StorageBackend {
+    #[default]
+    Csv,
+    Parquet,
+    Sqlite,
+    /// Nothing persisted, for dry runs
+    Memory,
+}
+
+impl FromStr for StorageBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(StorageBackend::Csv),
+            "parquet" => Ok(StorageBackend::Parquet),
+            "sqlite" => Ok(StorageBackend::Sqlite),
+            "memory" => Ok(StorageBackend::Memory),
+            _ => Err(format!("Unknown storage backend: {} (expected csv, parquet, sqlite or memory)", s)),
+        }
+    }
+}
+
+/// Persistence for the listing datasets
+///
+/// Writes are staged by `upsert_features`/`append_observations` and only made durable by `commit`
+pub(crate) trait ListingStore {
+    /// Reads everything stored for a dataset, None if nothing has been stored
+    ///
+    /// Columns may be in their stored types, callers cast to the dataset schema
+    fn read(&self, dataset: Dataset) -> Result<Option<DataFrame>>;
+
+    /// Lazy frame w/ exactly the dataset schema, None if nothing has been stored
+    fn scan(&self, dataset: Dataset) -> Result<Option<LazyFrame>> {
+        self.read(dataset)?
+            .map(|df| conform_lazy(df.lazy(), &dataset.schema()))
+            .transpose()
+    }
+
+    /// Lazy history w/ only the rows matching filter, None if nothing has been stored
+    fn scan_history(&self, filter: &HistoryFilter) -> Result<Option<LazyFrame>> {
+        self.scan(Dataset::History)?
+            .map(|history| filter.apply(history, self))
+            .transpose()
+    }
+
+    /// Stages the full set of known listings, stored rows w/ the same property id are replaced
+    fn upsert_features(&mut self, features: &DataFrame) -> Result<()>;
+
+    /// Stages history rows recorded since the last commit, stored rows are never rewritten
+    fn append_observations(&mut self, observations: &DataFrame) -> Result<()>;
+
+    /// Stages a scrape run's row, a stored row w/ the same run id is replaced
+    fn record_run(&mut self, run: &DataFrame) -> Result<()>;
+
+    /// Stages a derived dataset, the stored one is replaced whole at commit
+    fn replace_derived(&mut self, dataset: Dataset, df: &DataFrame) -> Result<()>;
+
+    /// Stages a full replacement of the stored history, for upgrades and force refreshes
+    ///
+    /// Observations appended in the same commit are added after it
+    fn replace_history(&mut self, history: &DataFrame) -> Result<()>;
+
+    /// Persists everything staged and marks the store as `SCHEMA_VERSION`
+    ///
+    /// Staged writes are dropped whether or not it succeeds. A failed commit leaves no staged
+    /// observations stored, so they can be staged again w/o duplicating history rows
+    fn commit(&mut self) -> Result<()>;
+
+    /// Drops everything staged since the last commit
+    fn discard(&mut self);
+
+    /// Schema version recorded by the last commit, None if the store predates version markers
+    fn recorded_version(&self) -> Result<Option<u32>> {
+        Ok(None)
+    }
+
+    /// Schema version of the stored data, inferred from its columns when unrecorded
+    ///
+    /// Empty stores are at the current version
+    fn schema_version(&self) -> Result<u32> {
+        if let Some(version) = self.recorded_version()? {
+            return Ok(version);
+        }
+        Ok(self.read(Dataset::Features)?.map_or(SCHEMA_VERSION, |features| infer_version(&features)))
+    }
+
+    /// Reads a previous version of a dataset, None if that generation doesn't exist
+    ///
+    /// Generation 1 is the most recent backup
+    fn read_backup(&self, _dataset: Dataset, _generation: usize) -> Result<Option<DataFrame>> {
+        Ok(None)
+    }
+
+    /// Makes a backup the current version of a dataset
+    fn restore_backup(&self, dataset: Dataset, _generation: usize) -> Result<()> {
+        Err(anyhow!("No backups of {} to restore", dataset.name()))
+    }
+
+    /// Moves unreadable data out of the way so the next commit can't overwrite it
+    fn set_aside(&self, dataset: Dataset) -> Result<PathBuf> {
+        Err(anyhow!("Rejected {} left in place", dataset.name()))
+    }
+
+    /// Loads the features dataset, one row per known listing, upgraded from `version`
+    fn load_known_listings(&self, force_refresh: bool, version: u32) -> (DataFrame, LoadOutcome) {
+        load_dataset(self, Dataset::Features, force_refresh, version, |df| {
+            // Only older steps need the stored history, upgraded so every step sees current columns
+            let history = match version < SCHEMA_VERSION {
+                true => self.read(Dataset::History).ok().flatten()
+                    .and_then(|history| migrate_from(version, history, |migration, history| (migration.history)(history, &df)).ok())
+                    .and_then(|history| conform_to_schema(&history, &Dataset::History.schema()).ok())
+                    .map_or_else(|| empty_frame(&Dataset::History.schema()), |(history, _)| history),
+                false => empty_frame(&Dataset::History.schema()),
+            };
+            migrate_from(version, df, |migration, df| (migration.features)(df, &history))
+        })
+    }
+
+    /// Loads the runs dataset, kept on a force refresh so earlier runs stay recorded
+    fn load_runs(&self, version: u32) -> (DataFrame, LoadOutcome) {
+        load_dataset(self, Dataset::Runs, false, version, Ok)
+    }
+
+    /// Loads the stored price events, derived again on the next save when history is replaced
+    fn load_price_events(&self, force_refresh: bool, version: u32) -> (DataFrame, LoadOutcome) {
+        load_dataset(self, Dataset::PriceEvents, force_refresh, version, Ok)
+    }
+
+    /// Loads the history dataset upgraded from `version`, rows w/o an id are keyed using the loaded features
+    fn load_history(&self, force_refresh: bool, version: u32, features: &DataFrame) -> (DataFrame, LoadOutcome) {
+        load_dataset(self, Dataset::History, force_refresh, version, |df| {
+            let df = migrate_from(version, df, |migration, df| (migration.history)(df, features))?;
+            // Listings claimed since their rows were stored only have the id in features
+            migrate_legacy_history(df, features)
+        })
+    }
+}
+
+/// Loads a dataset as a frame w/ exactly the dataset schema
+///
+/// `migrate` runs on the raw data before it's cast, for changes that need more than new null columns.
+/// Never fails: unreadable data is set aside and the newest readable backup is restored,
+/// if there is none an empty frame is returned
+fn load_dataset<S: ListingStore + ?Sized>(
+    store: &S,
+    dataset: Dataset,
+    force_refresh: bool,
+    version: u32,
+    migrate: impl Fn(DataFrame) -> PolarsResult<DataFrame>,
+) -> (DataFrame, LoadOutcome) {
+    let schema = dataset.schema();
+    if force_refresh {
+        return (empty_frame(&schema), LoadOutcome::Empty(EmptyReason::ForceRefresh));
+    }
+
+    let conform = |raw: DataFrame| migrate(raw)
+        .and_then(|df| conform_to_schema(&df, &schema))
+        .and_then(|(df, added)| match dataset {
+            Dataset::Runs => check_run_ids(&df),
+            _ => check_keys(&df),
+        }.map(|_| (df, added)))
+        .map_err(anyhow::Error::from);
+
+    let reason = match store.read(dataset) {
+        Ok(None) => return (empty_frame(&schema), LoadOutcome::Empty(EmptyReason::NotFound)),
+        Ok(Some(raw)) => match conform(raw) {
+            Ok((df, added)) if added.is_empty() && version == SCHEMA_VERSION => return (df, LoadOutcome::Loaded),
+            Ok((df, added)) => return (df, LoadOutcome::Migrated { from_version: version, added }),
+            Err(e) => format!("{:#}", e),
+        },
+        Err(e) => format!("{:#}", e),
+    };
+
+    match store.set_aside(dataset) {
+        Ok(rejected_path) => warn!("Rejected {} kept as {}", dataset.name(), rejected_path.display()),
+        Err(e) => warn!("{:#}", e),
+    }
+
+    // Fall back to the newest backup that loads
+    for generation in 1.. {
+        let backup = match store.read_backup(dataset, generation) {
+            Ok(Some(backup)) => backup,
+            Ok(None) => break,
+            Err(e) => {
+                warn!("Backup {} of {} unreadable: {:#}", generation, dataset.name(), e);
+                continue;
+            }
+        };
+        match conform(backup) {
+            Ok((df, _)) => {
+                if let Err(e) = store.restore_backup(dataset, generation) {
+                    warn!("{:#}", e);
+                }
+                return (df, LoadOutcome::Restored { generation, reason });
+            }
+            Err(e) => warn!("Backup {} of {} rejected: {:#}", generation, dataset.name(), e),
+        }
+    }
+
+    (empty_frame(&schema), LoadOutcome::Empty(EmptyReason::Rejected(reason)))
+}
+
+/// Casts each schema column of df to its dtype
+///
+/// Columns missing from files written by older versions are added as nulls and returned.
+/// Values that don't fit the dtype are an error rather than silently nulled
+pub(crate) fn conform_to_schema(df: &DataFrame, schema: &Schema) -> PolarsResult<(DataFrame, Vec<String>)> {
+    let mut added = vec![];
+    let cols = schema.iter()
+        .map(|(col_name, dtype)| match df.column(col_name) {
+            // CSV reads booleans as text, which polars won't cast
+            Ok(series) if series.dtype() == &DataType::Utf8 && dtype == &DataType::Boolean => parse_bools(series),
+            Ok(series) => series.strict_cast(dtype),
+            Err(_) => {
+                added.push(col_name.to_string());
+                Ok(Series::full_null(col_name, df.height(), dtype))
+            }
+        })
+        .collect::<PolarsResult<Vec<Series>>>()?;
+
+    Ok((DataFrame::new(cols)?, added))
+}
+
+/// `true`/`false` text as booleans, anything else is an error
+fn parse_bools(series: &Series) -> PolarsResult<Series> {
+    let bools = series.utf8()?.into_iter()
+        .map(|value| match value {
+            None | Some("") => Ok(None),
+            Some("true") => Ok(Some(true)),
+            Some("false") => Ok(Some(false)),
+            Some(other) => Err(PolarsError::ComputeError(format!("{:?} in boolean column {}", other, series.name()).into())),
+        })
+        .collect::<PolarsResult<BooleanChunked>>()?;
+    Ok(bools.with_name(series.name()).into_series())
+}
+
+/// Every row needs a property id, or an address for rows saved before ids
+///
+/// Catches truncated or foreign files that happen to parse. A missing column counts as all null
+pub(crate) fn check_keys(df: &DataFrame) -> PolarsResult<()> {
+    let is_null = |col_name: &str| match df.column(col_name) {
+        Ok(series) => series.is_null(),
+        Err(_) => BooleanChunked::full(col_name, true, df.height()),
+    };
+    let unkeyed = is_null("property_id") & is_null("addr_str");
+    match unkeyed.sum().unwrap_or(0) {
+        0 => Ok(()),
+        n => Err(PolarsError::ComputeError(format!("{} rows w/o a property id or address", n).into())),
+    }
+}
+
+/// Catches a runs file that parses but isn't a run log, e.g. truncated or foreign
+fn check_run_ids(runs: &DataFrame) -> PolarsResult<()> {
+    match runs.column("run_id")?.null_count() {
+        0 => Ok(()),
+        n => Err(PolarsError::ComputeError(format!("{} runs w/o a run id", n).into())),
+    }
+}
+
+/// Adds run rows to the stored runs, replacing stored rows w/ the same run id
+pub(crate) fn upsert_runs(stored: Option<&DataFrame>, runs: &DataFrame) -> PolarsResult<DataFrame> {
+    let schema = Dataset::Runs.schema();
+    let (runs, _) = conform_to_schema(runs, &schema)?;
+    let Some(stored) = stored else {
+        return Ok(runs);
+    };
+    let (stored, _) = conform_to_schema(stored, &schema)?;
+    let run_ids = runs.column("run_id")?.clone();
+    stored.lazy()
+        .filter(col("run_id").is_in(lit(run_ids)).not().or(col("run_id").is_null()))
+        .collect()?
+        .vstack(&runs)
+}
+
+/// Lazy `conform_to_schema`, casts and null columns are added to the query plan
+///
+/// Cast failures surface when the frame is collected
+pub(crate) fn conform_lazy(lf: LazyFrame, schema: &Schema) -> Result<LazyFrame> {
+    let file_schema = lf.schema().context("Failed to read dataset schema")?;
+    let exprs: Vec<Expr> = schema.iter()
+        .map(|(col_name, dtype)| match file_schema.contains(col_name) {
+            true => col(col_name).strict_cast(dtype.clone()),
+            false => lit(NULL).cast(dtype.clone()).alias(col_name),
+        })
+        .collect();
+
+    Ok(lf.select(exprs))
+}
+
+/// Features written before the first/last seen span, taken from the listing's history rows
+///
+/// Matched on addr_str, the only key older history has
+fn backfill_seen_span(features_df: DataFrame, history_df: &DataFrame) -> PolarsResult<DataFrame> {
+    if features_df.column("first_seen").is_ok() || features_df.column("addr_str").is_err() {
+        return Ok(features_df);
+    }
+
+    let span = history_df.clone()
+        .lazy()
+        .filter(col("addr_str").is_not_null())
+        .group_by([col("addr_str")])
+        .agg([
+            col("date").min().alias("first_seen"),
+            col("date").max().alias("last_seen"),
+        ]);
+
+    features_df.lazy()
+        .join(span, [col("addr_str")], [col("addr_str")], JoinArgs::new(JoinType::Left))
+        .collect()
+}
+
+/// Listings stored before v8 were all on the market as far as the scraper knew
+///
+/// Their status_date is left null, the status wasn't observed
+fn backfill_status(features_df: DataFrame) -> PolarsResult<DataFrame> {
+    if features_df.column("status").is_ok() {
+        return Ok(features_df);
+    }
+    features_df.lazy()
+        .with_column(lit("active").alias("status"))
+        .collect()
+}
+
+/// Times written before v5 are u32 unix seconds, in whatever type the store kept them as
+///
+/// Columns already holding datetimes (e.g. backfilled from upgraded history) are left as is
+fn unix_seconds_to_timestamps(df: DataFrame, col_names: &[&str]) -> PolarsResult<DataFrame> {
+    let exprs: Vec<Expr> = col_names.iter()
+        .filter(|col_name| df.column(col_name).is_ok_and(|series| !matches!(series.dtype(), DataType::Datetime(..))))
+        .map(|col_name| (col(col_name).strict_cast(DataType::Int64) * lit(1000i64)).strict_cast(timestamp_dtype()))
+        .collect();
+    if exprs.is_empty() {
+        return Ok(df);
+    }
+    df.lazy().with_columns(exprs).collect()
+}
+
+/// History written before listings were keyed on property id only has addr_str
+///
+/// Fills missing ids by looking up each address in the features dataset. Rows w/o a match stay null
+/// until the listing is seen again and claimed, after which the features row carries the id
+pub(crate) fn migrate_legacy_history(hist_df: DataFrame, features_df: &DataFrame) -> PolarsResult<DataFrame> {
+    let has_ids = hist_df.column("property_id").is_ok();
+    if has_ids && hist_df.column("property_id")?.null_count() == 0 {
+        return Ok(hist_df);
+    }
+    let has_keys = |df: &DataFrame| df.column("addr_str").is_ok() && df.column("property_id").is_ok();
+    if !has_keys(features_df) || hist_df.column("addr_str").is_err() {
+        return Ok(hist_df);
+    }
+
+    info!("Migrating legacy history, joining on addr_str for property ids");
+    let ids = features_df.clone()
+        .lazy()
+        .filter(col("property_id").is_not_null())
+        .select([col("addr_str"), col("property_id").cast(DataType::UInt64).alias("legacy_id")])
+        .unique(Some(vec!["addr_str".to_string()]), UniqueKeepStrategy::First);
+
+    let hist = match has_ids {
+        true => hist_df.lazy().with_column(col("property_id").strict_cast(DataType::UInt64)),
+        false => hist_df.lazy().with_column(lit(NULL).cast(DataType::UInt64).alias("property_id")),
+    };
+
+    hist.join(ids, [col("addr_str")], [col("addr_str")], JoinArgs::new(JoinType::Left))
+        .with_column(
+            when(col("property_id").is_null())
+                .then(col("legacy_id"))
+                .otherwise(col("property_id"))
+                .alias("property_id")
+        )
+        .select([col("*").exclude(["legacy_id"])])
+        .collect()
+}
+
+/// Frames shared by the store tests
+#[cfg(test)]
+pub(crate) mod test_frames {
+    use chrono::{DateTime, TimeZone, Utc};
+    use polars::prelude::*;
+
+    use crate::listing_structs::{CardSource, PriceHistory};
+
+    pub(crate) fn day(day: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 1, day, 12, 0, 0).unwrap()
+    }
+
+    /// History rows for (property id, price, date), recorded by `run_id`
+    pub(crate) fn observations(run_id: u64, rows: &[(u64, u32, DateTime<Utc>)]) -> DataFrame {
+        PriceHistory::to_frame(run_id, rows.iter()
+            .map(|(id, price, date)| {
+                let source = CardSource { url: String::from("https://www.redfin.com/zipcode/77532"), page: 1, position: 1 };
+                (*id, format!("{} Main St, Crosby, TX 77532", id), PriceHistory::new(*price, *date, source))
+            })
+            .collect())
+    }
+
+    /// Features rows for (property id, zip), addressed by id or by row index for legacy rows w/o one
+    pub(crate) fn features(rows: &[(Option<u64>, u32)]) -> DataFrame {
+        let ids: Vec<Option<u64>> = rows.iter().map(|(id, _)| *id).collect();
+        let addrs: Vec<String> = rows.iter().enumerate()
+            .map(|(row_idx, (id, _))| match id {
+                Some(id) => format!("{} Main St, Crosby, TX 77532", id),
+                None => format!("{} Old Rd, Crosby, TX 77532", row_idx),
+            })
+            .collect();
+        let zips: Vec<u32> = rows.iter().map(|(_, zip)| *zip).collect();
+        let df = DataFrame::new(vec![
+            Series::new("property_id", ids),
+            Series::new("addr_str", addrs),
+            Series::new("zip", zips),
+        ]).unwrap();
+        super::conform_to_schema(&df, &super::Dataset::Features.schema()).unwrap().0
+    }
+
+    /// (property id, price) of each history row, sorted
+    pub(crate) fn prices(history: &DataFrame) -> Vec<(u64, u32)> {
+        let ids = history.column("property_id").unwrap().cast(&DataType::UInt64).unwrap();
+        let prices = history.column("price").unwrap().cast(&DataType::UInt32).unwrap();
+        let mut rows: Vec<(u64, u32)> = ids.u64().unwrap().into_iter()
+            .zip(prices.u32().unwrap())
+            .map(|(id, price)| (id.unwrap(), price.unwrap()))
+            .collect();
+        rows.sort();
+        rows
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::test_frames::day;
+    use crate::listing_structs::{ListingsContainer, ObservationGranularity};
+    use std::fs;
+    use std::path::Path;
+
+    /// A v1 CSV store: address keyed listings and history w/ u32 second dates, no version marker
+    fn write_v1_store(dir: &Path) {
+        fs::write(dir.join("listing_features.csv"), "\
+url,beds,baths,sqft,lot_size,street,apt,city,state,zip,addr_str
+https://www.redfin.com/TX/Crosby/1-Main-St-77532/home/1,3,2,1500,7405,1 Main St,-1,Crosby,TX,77532,\"1 Main St, Crosby, TX 77532\"
+,4,3,2200,-1,2 Main St,-1,Crosby,TX,77532,\"2 Main St, Crosby, TX 77532\"
+").unwrap();
+        let secs = |d: u32| day(d).timestamp();
+        fs::write(dir.join("listing_history.csv"), format!("\
+addr_str,date,price
+\"1 Main St, Crosby, TX 77532\",{},100000
+\"1 Main St, Crosby, TX 77532\",{},95000
+\"2 Main St, Crosby, TX 77532\",{},200000
+", secs(1), secs(3), secs(2))).unwrap();
+    }
+
+    fn load(dir: &Path) -> ListingsContainer<FileStore> {
+        let mut container = ListingsContainer::new(FileStore::new(dir, FileFormat::Csv), false, false, ObservationGranularity::default());
+        container.initialize_datasets();
+        container
+    }
+
+    fn assert_upgraded(features: &DataFrame) {
+        assert_eq!(features.schema(), Dataset::Features.schema());
+        let millis = |col_name: &str| features.column(col_name).unwrap().cast(&DataType::Int64).unwrap().i64().unwrap().into_iter().collect::<Vec<_>>();
+        assert_eq!(millis("first_seen"), vec![Some(day(1).timestamp_millis()), Some(day(2).timestamp_millis())]);
+        assert_eq!(millis("last_seen"), vec![Some(day(3).timestamp_millis()), Some(day(2).timestamp_millis())]);
+        let statuses: Vec<Option<&str>> = features.column("status").unwrap().utf8().unwrap().into_iter().collect();
+        assert_eq!(statuses, vec![Some("active"), Some("active")]);
+        assert_eq!(features.column("property_id").unwrap().null_count(), 2);
+    }
+
+    #[test]
+    fn infers_unmarked_versions() {
+        let df = |col_names: &[&str]| DataFrame::new(col_names.iter().map(|name| Series::new(name, [1u32])).collect()).unwrap();
+        assert_eq!(infer_version(&df(&["addr_str"])), 1);
+        assert_eq!(infer_version(&df(&["addr_str", "latitude"])), 2);
+        assert_eq!(infer_version(&df(&["addr_str", "latitude", "property_id"])), 3);
+        assert_eq!(infer_version(&df(&["addr_str", "property_id", "first_seen"])), 4);
+    }
+
+    #[test]
+    fn upgrades_v1_store() {
+        let dir = tempfile::tempdir().unwrap();
+        write_v1_store(dir.path());
+        let store = FileStore::new(dir.path(), FileFormat::Csv);
+        assert_eq!(store.recorded_version().unwrap(), None);
+        assert_eq!(store.schema_version().unwrap(), 1);
+
+        let mut container = load(dir.path());
+        assert_upgraded(&container.listing_features);
+        let history = container.replacement_history.clone().expect("upgraded history pending a rewrite");
+        assert_eq!(history.schema(), Dataset::History.schema());
+        let dates: Vec<Option<i64>> = history.column("date").unwrap().cast(&DataType::Int64).unwrap().i64().unwrap().into_iter().collect();
+        assert_eq!(dates, vec![Some(day(1).timestamp_millis()), Some(day(3).timestamp_millis()), Some(day(2).timestamp_millis())]);
+
+        // Saved at the current version, the next load reads it as is
+        container.save_datasets();
+        assert_eq!(container.store.recorded_version().unwrap(), Some(SCHEMA_VERSION));
+        let container = load(dir.path());
+        assert!(container.replacement_history.is_none());
+        assert_upgraded(&container.listing_features);
+        let history = container.store.read(Dataset::History).unwrap().unwrap();
+        assert_eq!(history.height(), 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "newer than this build")]
+    fn refuses_newer_store() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("schema_version"), (SCHEMA_VERSION + 1).to_string()).unwrap();
+        load(dir.path());
+    }
+}
@@ -0,0 +1,900 @@
+use polars::prelude::*;
+use anyhow::{Context, Result};
+use tracing::{info, warn};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use super::{check_keys, conform_lazy, conform_to_schema, upsert_runs, Dataset, HistoryFilter, ListingStore, SCHEMA_VERSION};
+
+
+/// Previous versions kept of each dataset file, bak1 is the most recent
+const BACKUP_GENERATIONS: usize = 3;
+
+/// On-disk format of the dataset files
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub(crate) enum FileFormat {
+    #[default]
+    Csv,
+    Parquet,
+}
+
+impl FileFormat {
+    pub(crate) fn extension(&self) -> &'static str {
+        match self {
+            FileFormat::Csv => "csv",
+            FileFormat::Parquet => "parquet",
+        }
+    }
+}
+
+/// How history part files are arranged under the history directory
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub(crate) enum HistoryLayout {
+    /// All parts directly in the history directory
+    #[default]
+    Flat,
+    /// Hive style `zip=<zip>/date=<yyyy-mm-dd>/` directories, one part per partition per commit
+    Hive,
+}
+
+impl FromStr for HistoryLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "flat" => Ok(HistoryLayout::Flat),
+            "hive" => Ok(HistoryLayout::Hive),
+            _ => Err(format!("Unknown history layout: {} (expected flat or hive)", s)),
+        }
+    }
+}
+
+/// Partition value for rows w/o one, same as Hive/polars
+const DEFAULT_PARTITION: &str = "__HIVE_DEFAULT_PARTITION__";
+
+/// A stored history part and the partition values in its path, None if not partitioned by that key
+#[derive(Debug)]
+struct Part {
+    path: PathBuf,
+    zip: Option<u32>,
+    /// Days since the unix epoch (UTC)
+    day: Option<u32>,
+}
+
+/// Reads and writes the datasets in a single directory
+///
+/// Features are one file, rewritten each commit. History is a directory of part files,
+/// each commit adds a part and stored parts are never rewritten.
+/// Owns file naming, every path is built from `Dataset::name` and the format extension
+#[derive(Debug, Clone)]
+pub(crate) struct FileStore {
+    dir: PathBuf,
+    format: FileFormat,
+    layout: HistoryLayout,
+    staged_features: Option<DataFrame>,
+    staged_observations: Vec<DataFrame>,
+    staged_history: Option<DataFrame>,
+    staged_runs: Option<DataFrame>,
+    staged_derived: Vec<(Dataset, DataFrame)>,
+}
+
+impl FileStore {
+    pub(crate) fn new(dir: impl Into<PathBuf>, format: FileFormat) -> Self {
+        FileStore {
+            dir: dir.into(),
+            format,
+            layout: HistoryLayout::default(),
+            staged_features: None,
+            staged_observations: vec![],
+            staged_history: None,
+            staged_runs: None,
+            staged_derived: vec![],
+        }
+    }
+
+    /// Layout used for new history parts, parts in either layout are always read
+    pub(crate) fn with_layout(mut self, layout: HistoryLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    pub(crate) fn path(&self, dataset: Dataset) -> PathBuf {
+        self.dir.join(format!("{}.{}", dataset.name(), self.format.extension()))
+    }
+
+    pub(crate) fn backup_path(&self, dataset: Dataset, generation: usize) -> PathBuf {
+        self.dir.join(format!("{}.bak{}.{}", dataset.name(), generation, self.format.extension()))
+    }
+
+    fn temp_path(&self, dataset: Dataset) -> PathBuf {
+        self.dir.join(format!("{}.tmp.{}", dataset.name(), self.format.extension()))
+    }
+
+    /// Directory of history part files
+    pub(crate) fn parts_dir(&self) -> PathBuf {
+        self.dir.join(Dataset::History.name())
+    }
+
+    /// Stored history parts in both layouts, oldest first
+    ///
+    /// Temp and rejected files in the directory are skipped.
+    /// Finishes a history replacement interrupted by a crash first
+    fn parts(&self) -> Result<Vec<Part>> {
+        self.finish_history_swap()?;
+        let mut parts = vec![];
+        self.collect_parts(&self.parts_dir(), None, None, &mut parts)?;
+        parts.sort_by(|a, b| a.path.file_name().cmp(&b.path.file_name()));
+        Ok(parts)
+    }
+
+    /// Walks `zip=`/`date=` directories, picking up the partition values on the way down
+    fn collect_parts(&self, dir: &Path, zip: Option<u32>, day: Option<u32>, parts: &mut Vec<Part>) -> Result<()> {
+        if !dir.exists() {
+            return Ok(());
+        }
+
+        let extension = format!(".{}", self.format.extension());
+        for entry in fs::read_dir(dir).with_context(|| format!("Failed to list {}", dir.display()))? {
+            let path = entry?.path();
+            let file_name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default().to_string();
+
+            if path.is_dir() {
+                match file_name.split_once('=') {
+                    Some(("zip", value)) => self.collect_parts(&path, value.parse().ok(), day, parts)?,
+                    Some(("date", value)) => self.collect_parts(&path, zip, parse_day(value), parts)?,
+                    _ => warn!("Skipping unexpected directory {}", path.display()),
+                }
+                continue;
+            }
+
+            let is_part = file_name.strip_prefix("part-")
+                .and_then(|name| name.strip_suffix(extension.as_str()))
+                .is_some_and(|stamp| stamp.chars().all(|c| c.is_ascii_digit()));
+            if is_part {
+                parts.push(Part { path, zip, day });
+            }
+        }
+        Ok(())
+    }
+
+    /// History file written before history was split into parts, read but never written
+    ///
+    /// Call after `parts`, which may move it aside
+    fn legacy_history_path(&self) -> Option<PathBuf> {
+        Some(self.path(Dataset::History)).filter(|path| path.exists())
+    }
+
+    /// Reads the legacy history file and every part in their stored types
+    ///
+    /// Columns missing from older files are null. A part that can't be read is set aside and
+    /// skipped, the rest of the history still loads
+    fn read_history(&self) -> Result<Option<DataFrame>> {
+        let mut pieces = vec![];
+
+        let parts = self.parts()?;
+        if let Some(path) = self.legacy_history_path() {
+            pieces.push(self.read_path(&path)?.expect("legacy history exists"));
+        }
+
+        for Part { path, .. } in parts {
+            let part = self.read_path(&path).and_then(|part| {
+                let part = part.expect("part exists");
+                check_keys(&part)?;
+                Ok(part)
+            });
+            let part = match part {
+                Ok(part) => part,
+                Err(e) => {
+                    warn!("Skipping history part {}: {:#}", path.display(), e);
+                    match set_aside_path(&path) {
+                        Ok(rejected_path) => warn!("Rejected part kept as {}", rejected_path.display()),
+                        Err(e) => warn!("{:#}", e),
+                    }
+                    continue;
+                }
+            };
+            pieces.push(part);
+        }
+
+        match pieces.len() {
+            0 => Ok(None),
+            1 => Ok(pieces.pop()),
+            _ => Ok(Some(polars::functions::concat_df_diagonal(&pieces)?)),
+        }
+    }
+
+    fn read_path(&self, path: &Path) -> Result<Option<DataFrame>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let df = match self.format {
+            FileFormat::Csv => CsvReader::from_path(path)
+                .with_context(|| format!("Failed to open {}", path.display()))?
+                .has_header(true)
+                .infer_schema(Some(0))
+                .finish(),
+            FileFormat::Parquet => ParquetReader::new(File::open(path)
+                .with_context(|| format!("Failed to open {}", path.display()))?)
+                .finish(),
+        }.with_context(|| format!("Failed to parse {}", path.display()))?;
+
+        Ok(Some(df))
+    }
+
+    /// Writes df to path and syncs it to disk
+    fn write_synced(&self, path: &Path, df: &DataFrame) -> Result<()> {
+        let mut file = File::create(path)
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+        match self.format {
+            FileFormat::Csv => CsvWriter::new(&mut file).finish(&mut df.clone()),
+            FileFormat::Parquet => ParquetWriter::new(&mut file).finish(&mut df.clone()).map(|_| ()),
+        }.with_context(|| format!("Failed to write {}", path.display()))?;
+        file.sync_all()
+            .with_context(|| format!("Failed to sync {}", path.display()))
+    }
+
+    /// Writes a dataset, creating the data directory if needed
+    ///
+    /// Data goes to a temp file that is synced and renamed over the old version, so a crash
+    /// mid-write leaves the previous file intact. The previous version is kept as bak1
+    fn write(&self, dataset: Dataset, df: &DataFrame) -> Result<()> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Failed to create data dir {}", self.dir.display()))?;
+
+        let path = self.path(dataset);
+        let temp_path = self.temp_path(dataset);
+        self.write_synced(&temp_path, df)?;
+
+        if path.exists() {
+            self.rotate_backups(dataset)?;
+        }
+        fs::rename(&temp_path, &path)
+            .with_context(|| format!("Failed to replace {}", path.display()))?;
+        sync_dir(&self.dir);
+
+        info!("Saved {} rows to {}", df.height(), path.display());
+        Ok(())
+    }
+
+    /// Adds observations to the history as new part files, one per partition in the hive layout
+    ///
+    /// Rows are partitioned by the zip of their listing in features and the UTC day they were seen.
+    /// Returns the parts written, if any part fails the ones already written are removed
+    fn append_observations_parts(&self, root: &Path, observations: &DataFrame, features: Option<&DataFrame>) -> Result<Vec<PathBuf>> {
+        if self.layout == HistoryLayout::Flat {
+            return self.append_part(root, observations).map(|path| vec![path]);
+        }
+
+        let zips: HashMap<u64, u32> = match features {
+            Some(features) => {
+                let (features, _) = conform_to_schema(features, &Dataset::Features.schema())?;
+                let ids = features.column("property_id")?.u64()?.clone();
+                let zips = features.column("zip")?.u32()?.clone();
+                ids.into_iter().zip(&zips)
+                    .filter_map(|(id, zip)| Some((id?, zip?)))
+                    .collect()
+            }
+            None => HashMap::new(),
+        };
+
+        let ids = observations.column("property_id")?.u64()?;
+        let millis = observations.column("date")?.cast(&DataType::Int64)?;
+        let mut partitions: BTreeMap<(Option<u32>, Option<u32>), Vec<IdxSize>> = BTreeMap::new();
+        for (row_idx, (id, millis)) in ids.into_iter().zip(millis.i64()?).enumerate() {
+            let zip = id.and_then(|id| zips.get(&id).copied());
+            let day = millis.and_then(|millis| u32::try_from(millis.div_euclid(MILLIS_PER_DAY)).ok());
+            partitions.entry((zip, day)).or_default().push(row_idx as IdxSize);
+        }
+
+        let mut written = vec![];
+        let appended = partitions.into_iter().try_for_each(|((zip, day), row_idxs)| {
+            let zip = zip.map_or(DEFAULT_PARTITION.to_string(), |zip| zip.to_string());
+            let day = day.and_then(format_day).unwrap_or(DEFAULT_PARTITION.to_string());
+            let partition_dir = root.join(format!("zip={}", zip)).join(format!("date={}", day));
+            let rows = observations.take(&IdxCa::from_vec("rows", row_idxs))?;
+            written.push(self.append_part(&partition_dir, &rows)?);
+            Ok(())
+        });
+        if let Err(e) = appended {
+            remove_parts(&written);
+            return Err(e);
+        }
+        Ok(written)
+    }
+
+    /// Replaces the whole history w/ new parts
+    ///
+    /// Parts are written to a staging directory marked complete once synced, then the old history
+    /// is archived and the staging directory renamed into place. A crash at any point leaves either
+    /// the old history or a complete staging directory that the next read swaps in
+    fn replace_history_parts(&self, history: &DataFrame, features: Option<&DataFrame>) -> Result<()> {
+        let staging = self.staging_dir();
+        if staging.exists() {
+            fs::remove_dir_all(&staging)
+                .with_context(|| format!("Failed to clear {}", staging.display()))?;
+        }
+        fs::create_dir_all(&staging)
+            .with_context(|| format!("Failed to create {}", staging.display()))?;
+
+        if history.height() > 0 {
+            self.append_observations_parts(&staging, history, features)?;
+        }
+        File::create(staging.join(SWAP_COMPLETE))
+            .and_then(|marker| marker.sync_all())
+            .with_context(|| format!("Failed to mark {} complete", staging.display()))?;
+        sync_dir(&staging);
+
+        self.finish_history_swap()
+    }
+
+    fn staging_dir(&self) -> PathBuf {
+        self.dir.join(format!("{}.replacing", Dataset::History.name()))
+    }
+
+    /// Archives the current history and moves a complete staging directory into its place
+    ///
+    /// An incomplete staging directory is from a crash mid-write and is removed
+    fn finish_history_swap(&self) -> Result<()> {
+        let staging = self.staging_dir();
+        if !staging.exists() {
+            return Ok(());
+        }
+        if !staging.join(SWAP_COMPLETE).exists() {
+            warn!("Removing incomplete history replacement {}", staging.display());
+            return fs::remove_dir_all(&staging)
+                .with_context(|| format!("Failed to remove {}", staging.display()));
+        }
+
+        let mut unix_time = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_secs();
+        let mut archive = self.dir.join(format!("{}.replaced-{}", Dataset::History.name(), unix_time));
+        while archive.exists() {
+            unix_time += 1;
+            archive = self.dir.join(format!("{}.replaced-{}", Dataset::History.name(), unix_time));
+        }
+        let parts_dir = self.parts_dir();
+        let legacy_path = self.path(Dataset::History);
+        if parts_dir.exists() || legacy_path.exists() {
+            fs::create_dir_all(&archive)
+                .with_context(|| format!("Failed to create {}", archive.display()))?;
+        }
+        if parts_dir.exists() {
+            fs::rename(&parts_dir, archive.join(Dataset::History.name()))
+                .with_context(|| format!("Failed to archive {}", parts_dir.display()))?;
+        }
+        if legacy_path.exists() {
+            let file_name = legacy_path.file_name().expect("dataset path has a file name");
+            fs::rename(&legacy_path, archive.join(file_name))
+                .with_context(|| format!("Failed to archive {}", legacy_path.display()))?;
+        }
+
+        fs::rename(&staging, &parts_dir)
+            .with_context(|| format!("Failed to move {} into place", staging.display()))?;
+        let _ = fs::remove_file(parts_dir.join(SWAP_COMPLETE));
+        sync_dir(&self.dir);
+
+        if archive.exists() {
+            info!("Replaced history, previous version archived in {}", archive.display());
+        }
+        Ok(())
+    }
+
+    fn version_path(&self) -> PathBuf {
+        self.dir.join("schema_version")
+    }
+
+    /// Records the schema version the datasets were written w/, temp file + rename like the datasets
+    fn write_version(&self) -> Result<()> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Failed to create data dir {}", self.dir.display()))?;
+        let path = self.version_path();
+        let temp_path = self.dir.join("schema_version.tmp");
+        fs::write(&temp_path, SCHEMA_VERSION.to_string())
+            .with_context(|| format!("Failed to write {}", temp_path.display()))?;
+        fs::rename(&temp_path, &path)
+            .with_context(|| format!("Failed to replace {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Adds rows as a new part file in parts_dir, returning its path
+    ///
+    /// Written under a temp name and renamed, so a crash never leaves a partial part.
+    /// Only the new rows are written, stored parts are left untouched
+    fn append_part(&self, parts_dir: &Path, df: &DataFrame) -> Result<PathBuf> {
+        fs::create_dir_all(parts_dir)
+            .with_context(|| format!("Failed to create {}", parts_dir.display()))?;
+
+        // Nanosecond stamps keep parts unique and in commit order when sorted by name
+        let mut stamp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_nanos();
+        let mut path = parts_dir.join(format!("part-{:020}.{}", stamp, self.format.extension()));
+        while path.exists() {
+            stamp += 1;
+            path = parts_dir.join(format!("part-{:020}.{}", stamp, self.format.extension()));
+        }
+        let temp_path = parts_dir.join(format!("part-{:020}.tmp.{}", stamp, self.format.extension()));
+
+        self.write_synced(&temp_path, df)?;
+        fs::rename(&temp_path, &path)
+            .with_context(|| format!("Failed to add {}", path.display()))?;
+        sync_dir(parts_dir);
+
+        info!("Appended {} rows to {}", df.height(), path.display());
+        Ok(path)
+    }
+
+    /// Scans each file cast to the dataset schema, None if there are none
+    fn scan_paths(&self, dataset: Dataset, paths: &[PathBuf]) -> Result<Option<LazyFrame>> {
+        if paths.is_empty() {
+            return Ok(None);
+        }
+
+        let schema = dataset.schema();
+        let lfs = paths.iter()
+            .map(|path| {
+                let lf = match self.format {
+                    FileFormat::Csv => LazyCsvReader::new(path)
+                        .has_header(true)
+                        .with_infer_schema_length(Some(0))
+                        .finish(),
+                    FileFormat::Parquet => LazyFrame::scan_parquet(path, ScanArgsParquet::default()),
+                }.with_context(|| format!("Failed to scan {}", path.display()))?;
+                conform_lazy(lf, &schema)
+            })
+            .collect::<Result<Vec<LazyFrame>>>()?;
+
+        Ok(Some(concat(lfs, UnionArgs::default())?))
+    }
+
+    /// Shifts each backup one generation older and keeps the current file as bak1
+    fn rotate_backups(&self, dataset: Dataset) -> Result<()> {
+        for generation in (1..BACKUP_GENERATIONS).rev() {
+            let backup = self.backup_path(dataset, generation);
+            if backup.exists() {
+                fs::rename(&backup, self.backup_path(dataset, generation + 1))
+                    .with_context(|| format!("Failed to rotate {}", backup.display()))?;
+            }
+        }
+
+        // Hard link so the current file stays in place until the rename replaces it
+        let path = self.path(dataset);
+        let newest = self.backup_path(dataset, 1);
+        if fs::hard_link(&path, &newest).is_err() {
+            fs::copy(&path, &newest)
+                .with_context(|| format!("Failed to back up {}", path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+const MILLIS_PER_DAY: i64 = 86_400_000;
+
+/// Written last to a history staging directory, the replacement is only swapped in if present
+const SWAP_COMPLETE: &str = "_complete";
+
+/// `yyyy-mm-dd` partition value to days since the unix epoch
+fn parse_day(value: &str) -> Option<u32> {
+    let date = chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?;
+    let epoch = chrono::NaiveDate::from_ymd_opt(1970, 1, 1)?;
+    u32::try_from((date - epoch).num_days()).ok()
+}
+
+fn format_day(day: u32) -> Option<String> {
+    let epoch = chrono::NaiveDate::from_ymd_opt(1970, 1, 1)?;
+    let date = epoch.checked_add_days(chrono::Days::new(day as u64))?;
+    Some(date.format("%Y-%m-%d").to_string())
+}
+
+/// Renames an unreadable file to `<name>.rejected-<unix time>.<ext>` so nothing overwrites it
+fn set_aside_path(path: &Path) -> Result<PathBuf> {
+    let unix_time = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_secs();
+    let stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default();
+    let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or_default();
+    let rejected_path = path.with_file_name(format!("{}.rejected-{}.{}", stem, unix_time, extension));
+    fs::rename(path, &rejected_path)
+        .with_context(|| format!("Failed to move {} aside", path.display()))?;
+    Ok(rejected_path)
+}
+
+/// Removes parts appended by a commit that then failed, so retrying it doesn't duplicate their rows
+fn remove_parts(paths: &[PathBuf]) {
+    for path in paths {
+        match fs::remove_file(path) {
+            Ok(()) => info!("Removed {}", path.display()),
+            Err(e) => warn!("Failed to remove {}, its rows may be duplicated: {}", path.display(), e),
+        }
+    }
+}
+
+/// Makes renames in `dir` durable, best effort (not supported on every platform)
+fn sync_dir(dir: &Path) {
+    if let Ok(dir) = File::open(dir) {
+        let _ = dir.sync_all();
+    }
+}
+
+impl FileStore {
+    /// History is written first and features plus the version marker last, so a failed commit
+    /// never leaves listings in features w/o their observations. Parts it appended are removed
+    /// and features put back if a later step fails, so a retry doesn't append them again.
+    /// Every other write replaces a whole file and is safe to repeat
+    fn commit_staged(&self) -> Result<()> {
+        let features = self.staged_features.as_ref();
+        let replacement = self.staged_history.as_ref();
+        let mut observations = match replacement {
+            Some(history) => conform_to_schema(history, &Dataset::History.schema())?.0,
+            None => super::empty_frame(&Dataset::History.schema()),
+        };
+        for staged in self.staged_observations.iter() {
+            observations.vstack_mut(staged)?;
+        }
+
+        let stored_features = match (self.layout, features) {
+            (HistoryLayout::Hive, None) => self.read(Dataset::Features)?,
+            _ => None,
+        };
+        let partition_features = features.or(stored_features.as_ref());
+        let appended = match replacement {
+            Some(_) => {
+                self.replace_history_parts(&observations, partition_features)?;
+                vec![]
+            }
+            None if observations.height() > 0 => self.append_observations_parts(&self.parts_dir(), &observations, partition_features)?,
+            None => vec![],
+        };
+
+        self.commit_datasets(features).inspect_err(|_| remove_parts(&appended))
+    }
+
+    /// Writes everything but history, features are put back if the version can't be recorded
+    fn commit_datasets(&self, features: Option<&DataFrame>) -> Result<()> {
+        // Runs are a small log, rewritten whole w/ the stored rows kept
+        if let Some(runs) = self.staged_runs.as_ref() {
+            let stored = self.read(Dataset::Runs)?;
+            self.write(Dataset::Runs, &upsert_runs(stored.as_ref(), runs)?)?;
+        }
+        for (dataset, df) in self.staged_derived.iter() {
+            self.write(*dataset, df)?;
+        }
+
+        let Some(features) = features else {
+            return self.write_version();
+        };
+        let replaced = self.path(Dataset::Features).exists();
+        self.write(Dataset::Features, features)?;
+        self.write_version().inspect_err(|_| self.revert_write(Dataset::Features, replaced))
+    }
+
+    /// Puts back the version of a dataset the last write replaced (now bak1), or removes it if there was none
+    fn revert_write(&self, dataset: Dataset, replaced: bool) {
+        let reverted = match replaced {
+            true => self.restore_backup(dataset, 1),
+            false => fs::remove_file(self.path(dataset))
+                .with_context(|| format!("Failed to remove {}", self.path(dataset).display())),
+        };
+        match reverted {
+            Ok(()) => info!("Reverted {}", self.path(dataset).display()),
+            Err(e) => warn!("Failed to revert {}: {:#}", dataset.name(), e),
+        }
+    }
+}
+
+impl ListingStore for FileStore {
+    /// CSV features columns are read as strings, history is cast to its schema
+    fn read(&self, dataset: Dataset) -> Result<Option<DataFrame>> {
+        match dataset {
+            Dataset::Features | Dataset::Runs | Dataset::PriceEvents => self.read_path(&self.path(dataset)),
+            Dataset::History => self.read_history(),
+        }
+    }
+
+    fn read_backup(&self, dataset: Dataset, generation: usize) -> Result<Option<DataFrame>> {
+        if generation > BACKUP_GENERATIONS {
+            return Ok(None);
+        }
+        self.read_path(&self.backup_path(dataset, generation))
+    }
+
+    /// Copies the backup over the current file so later commits build on it
+    fn restore_backup(&self, dataset: Dataset, generation: usize) -> Result<()> {
+        let backup = self.backup_path(dataset, generation);
+        let temp_path = self.temp_path(dataset);
+        fs::copy(&backup, &temp_path)
+            .with_context(|| format!("Failed to copy {}", backup.display()))?;
+        fs::rename(&temp_path, self.path(dataset))
+            .with_context(|| format!("Failed to restore {}", backup.display()))?;
+        sync_dir(&self.dir);
+        Ok(())
+    }
+
+    /// Filters and selects on the returned frame are pushed down to the reader,
+    /// so only matching row groups/columns are loaded
+    fn scan(&self, dataset: Dataset) -> Result<Option<LazyFrame>> {
+        let paths = match dataset {
+            Dataset::Features | Dataset::Runs | Dataset::PriceEvents => Some(self.path(dataset)).filter(|path| path.exists()).into_iter().collect(),
+            Dataset::History => {
+                let parts = self.parts()?;
+                self.legacy_history_path().into_iter()
+                    .chain(parts.into_iter().map(|part| part.path))
+                    .collect::<Vec<_>>()
+            }
+        };
+        self.scan_paths(dataset, &paths)
+    }
+
+    /// Only parts whose partition could match are scanned, the legacy file and flat parts always are
+    fn scan_history(&self, filter: &HistoryFilter) -> Result<Option<LazyFrame>> {
+        let parts = self.parts()?;
+        let part_count = parts.len();
+        let paths: Vec<PathBuf> = self.legacy_history_path().into_iter()
+            .chain(parts.into_iter()
+                .filter(|part| filter.may_match(part.zip, part.day))
+                .map(|part| part.path))
+            .collect();
+        info!("Scanning {} of {} history parts", paths.len(), part_count);
+
+        self.scan_paths(Dataset::History, &paths)?
+            .map(|history| filter.apply(history, self))
+            .transpose()
+    }
+
+    fn upsert_features(&mut self, features: &DataFrame) -> Result<()> {
+        self.staged_features = Some(features.clone());
+        Ok(())
+    }
+
+    fn append_observations(&mut self, observations: &DataFrame) -> Result<()> {
+        self.staged_observations.push(observations.clone());
+        Ok(())
+    }
+
+    fn replace_history(&mut self, history: &DataFrame) -> Result<()> {
+        self.staged_history = Some(history.clone());
+        Ok(())
+    }
+
+    fn record_run(&mut self, run: &DataFrame) -> Result<()> {
+        self.staged_runs = Some(upsert_runs(self.staged_runs.as_ref(), run)?);
+        Ok(())
+    }
+
+    fn replace_derived(&mut self, dataset: Dataset, df: &DataFrame) -> Result<()> {
+        self.staged_derived.push((dataset, df.clone()));
+        Ok(())
+    }
+
+    fn recorded_version(&self) -> Result<Option<u32>> {
+        let path = self.version_path();
+        if !path.exists() {
+            return Ok(None);
+        }
+        let version = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let version = version.trim().parse()
+            .with_context(|| format!("Unparsable schema version in {}: {}", path.display(), version.trim()))?;
+        Ok(Some(version))
+    }
+
+    /// Features, runs and derived datasets are rewritten whole, new observations become new history parts
+    ///
+    /// A staged history replacement swaps out every stored part instead
+    fn commit(&mut self) -> Result<()> {
+        let committed = self.commit_staged();
+        self.discard();
+        committed
+    }
+
+    fn discard(&mut self) {
+        self.staged_features = None;
+        self.staged_history = None;
+        self.staged_runs = None;
+        self.staged_observations.clear();
+        self.staged_derived.clear();
+    }
+
+    /// Parts are set aside individually when read, for history this only moves the legacy file
+    fn set_aside(&self, dataset: Dataset) -> Result<PathBuf> {
+        set_aside_path(&self.path(dataset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{DateTime, TimeZone, Utc};
+    use crate::listing_structs::{ListingsContainer, ObservationGranularity, ScrapeRun};
+    use crate::storage::test_frames::{day, features, observations, prices};
+
+    /// Saves a run through a store on dir, then loads it back in a second container as the next run would
+    fn save_then_load(dir: &Path, format: FileFormat, layout: HistoryLayout) {
+        let store = FileStore::new(dir, format).with_layout(layout);
+        let mut first = ListingsContainer::new(store, false, false, ObservationGranularity::default());
+        first.initialize_datasets();
+        first.listing_features = features(&[(Some(1), 77532), (Some(2), 77002)]);
+        first.new_observations = observations(first.run.run_id, &[(1, 100, day(1)), (2, 200, day(1))]);
+        first.save_datasets();
+
+        let store = FileStore::new(dir, format).with_layout(layout);
+        let mut second = ListingsContainer::new(store, false, false, ObservationGranularity::default());
+        second.initialize_datasets();
+        assert!(second.listing_features.frame_equal_missing(&first.listing_features), "{:?} {:?}", format, layout);
+        assert!(second.listing_index.contains(1) && second.listing_index.contains(2));
+        second.load_zip_observations(77532);
+        assert_eq!(second.listing_index.last_observation(1), Some((day(1), 100)));
+        assert_eq!(second.listing_index.last_observation(2), None);
+        assert_eq!(second.last_update, first.run.finished_at.map(|finished| Utc.timestamp_millis_opt(finished.timestamp_millis()).unwrap()));
+
+        // The second run's observations are added to the first's
+        second.new_observations = observations(second.run.run_id, &[(1, 90, day(2))]);
+        second.save_datasets();
+        let history = second.store.read(Dataset::History).unwrap().unwrap();
+        assert_eq!(prices(&history), vec![(1, 90), (1, 100), (2, 200)]);
+        let runs = second.store.read(Dataset::Runs).unwrap().unwrap();
+        assert_eq!(runs.height(), 2);
+        assert_eq!(second.price_events.height(), 1);
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        save_then_load(dir.path(), FileFormat::Csv, HistoryLayout::Flat);
+        assert!(dir.path().join("listing_features.csv").exists());
+    }
+
+    #[test]
+    fn parquet_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        save_then_load(dir.path(), FileFormat::Parquet, HistoryLayout::Flat);
+        assert!(dir.path().join("listing_features.parquet").exists());
+    }
+
+    #[test]
+    fn hive_round_trip() {
+        for format in [FileFormat::Csv, FileFormat::Parquet] {
+            let dir = tempfile::tempdir().unwrap();
+            save_then_load(dir.path(), format, HistoryLayout::Hive);
+            assert!(dir.path().join("listing_history/zip=77532/date=2024-01-01").is_dir());
+        }
+    }
+
+    #[test]
+    fn saves_keep_backups() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = FileStore::new(dir.path(), FileFormat::Csv);
+        for ids in [vec![(Some(1), 77532)], vec![(Some(1), 77532), (Some(2), 77532)]] {
+            store.upsert_features(&features(&ids)).unwrap();
+            store.commit().unwrap();
+        }
+        assert_eq!(store.read(Dataset::Features).unwrap().unwrap().height(), 2);
+        assert_eq!(store.read_backup(Dataset::Features, 1).unwrap().unwrap().height(), 1);
+        assert!(store.read_backup(Dataset::Features, 2).unwrap().is_none());
+    }
+
+    /// Runs a scrape on the datasets in dir that observed listing 1 at each (price, date)
+    fn scrape(dir: &Path, format: FileFormat, observed: &[(u32, DateTime<Utc>)]) -> ListingsContainer<FileStore> {
+        let mut container = ListingsContainer::new(FileStore::new(dir, format), false, false, ObservationGranularity::default());
+        container.initialize_datasets();
+        container.run = ScrapeRun::new(observed[0].1);
+        container.listing_features = features(&[(Some(1), 77532)]);
+        let rows: Vec<(u64, u32, DateTime<Utc>)> = observed.iter().map(|(price, date)| (1, *price, *date)).collect();
+        container.new_observations = observations(container.run.run_id, &rows);
+        container.save_datasets();
+        container
+    }
+
+    /// Overwrites a dataset w/ something that reads but isn't that dataset
+    fn corrupt(store: &FileStore, dataset: Dataset) {
+        fs::write(store.path(dataset), "garbage\n1\n").unwrap();
+    }
+
+    fn rejected_count(dir: &Path, dataset: Dataset) -> usize {
+        fs::read_dir(dir).unwrap()
+            .filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().starts_with(&format!("{}.rejected-", dataset.name())))
+            .count()
+    }
+
+    #[test]
+    fn corrupt_runs_restored_from_backup() {
+        for format in [FileFormat::Csv, FileFormat::Parquet] {
+            let dir = tempfile::tempdir().unwrap();
+            scrape(dir.path(), format, &[(100, day(1))]);
+            let second = scrape(dir.path(), format, &[(90, day(2))]);
+            let third = scrape(dir.path(), format, &[(80, day(3))]);
+            corrupt(&third.store, Dataset::Runs);
+
+            let fourth = scrape(dir.path(), format, &[(70, day(4))]);
+            assert_eq!(rejected_count(dir.path(), Dataset::Runs), 1);
+            // bak1 held the runs before the third
+            let runs = fourth.store.read(Dataset::Runs).unwrap().unwrap();
+            let (runs, _) = conform_to_schema(&runs, &Dataset::Runs.schema()).unwrap();
+            let run_ids: Vec<u64> = runs.column("run_id").unwrap().u64().unwrap().into_no_null_iter().collect();
+            assert_eq!(run_ids, vec![day(1).timestamp_millis() as u64, second.run.run_id, fourth.run.run_id], "{:?}", format);
+
+            // The fourth run's observation was saved despite the corrupt runs
+            let history = fourth.store.read(Dataset::History).unwrap().unwrap();
+            assert_eq!(prices(&history), vec![(1, 70), (1, 80), (1, 90), (1, 100)]);
+        }
+    }
+
+    #[test]
+    fn corrupt_price_events_restored_or_rebuilt() {
+        let dir = tempfile::tempdir().unwrap();
+        scrape(dir.path(), FileFormat::Csv, &[(100, day(1))]);
+        scrape(dir.path(), FileFormat::Csv, &[(90, day(2))]);
+        let third = scrape(dir.path(), FileFormat::Csv, &[(80, day(3))]);
+        assert_eq!(third.price_events.height(), 2);
+
+        // bak1 has the events before the third run
+        corrupt(&third.store, Dataset::PriceEvents);
+        let mut container = ListingsContainer::new(FileStore::new(dir.path(), FileFormat::Csv), false, false, ObservationGranularity::default());
+        container.initialize_datasets();
+        assert_eq!(container.price_events.height(), 1);
+        assert_eq!(rejected_count(dir.path(), Dataset::PriceEvents), 1);
+        assert_eq!(third.store.read(Dataset::PriceEvents).unwrap().unwrap().height(), 1);
+
+        // W/o a usable backup they're derived from history
+        corrupt(&third.store, Dataset::PriceEvents);
+        for generation in 1..=BACKUP_GENERATIONS {
+            let _ = fs::remove_file(third.store.backup_path(Dataset::PriceEvents, generation));
+        }
+        let fourth = scrape(dir.path(), FileFormat::Csv, &[(70, day(4))]);
+        assert_eq!(fourth.price_events.height(), 3);
+        assert_eq!(fourth.store.read(Dataset::PriceEvents).unwrap().unwrap().height(), 3);
+        assert_eq!(prices(&fourth.store.read(Dataset::History).unwrap().unwrap()), vec![(1, 70), (1, 80), (1, 90), (1, 100)]);
+    }
+
+    #[test]
+    fn failed_commit_leaves_features_unchanged() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = FileStore::new(dir.path(), FileFormat::Csv);
+        let run = ScrapeRun::new(day(1));
+        let stage = |store: &mut FileStore, ids: &[(Option<u64>, u32)], observed: &[(u64, u32, DateTime<Utc>)]| {
+            store.record_run(&run.to_frame()).unwrap();
+            store.upsert_features(&features(ids)).unwrap();
+            store.append_observations(&observations(run.run_id, observed)).unwrap();
+        };
+
+        // Nothing to put back on the first write
+        fs::create_dir_all(dir.path().join("schema_version.tmp")).unwrap();
+        stage(&mut store, &[(Some(1), 77532)], &[(1, 100, day(1))]);
+        assert!(store.commit().is_err());
+        assert!(store.read(Dataset::Features).unwrap().is_none());
+        fs::remove_dir(dir.path().join("schema_version.tmp")).unwrap();
+        stage(&mut store, &[(Some(1), 77532)], &[(1, 100, day(1))]);
+        store.commit().unwrap();
+
+        // A failed runs write stops the commit before features, a failed version write puts them back
+        for blocked in [store.temp_path(Dataset::Runs), dir.path().join("schema_version.tmp")] {
+            fs::create_dir_all(&blocked).unwrap();
+            stage(&mut store, &[(Some(1), 77532), (Some(2), 77532)], &[(2, 200, day(2))]);
+            assert!(store.commit().is_err());
+            assert_eq!(store.read(Dataset::Features).unwrap().unwrap().height(), 1, "{}", blocked.display());
+            assert_eq!(prices(&store.read(Dataset::History).unwrap().unwrap()), vec![(1, 100)]);
+            fs::remove_dir(&blocked).unwrap();
+        }
+    }
+
+    #[test]
+    fn failed_commit_leaves_no_parts() {
+        for layout in [HistoryLayout::Flat, HistoryLayout::Hive] {
+            let dir = tempfile::tempdir().unwrap();
+            let mut store = FileStore::new(dir.path(), FileFormat::Csv).with_layout(layout);
+            let history = observations(1, &[(1, 100, day(1)), (2, 200, day(2))]);
+
+            // The version can't be written over a directory
+            fs::create_dir_all(dir.path().join("schema_version.tmp")).unwrap();
+            store.append_observations(&history).unwrap();
+            assert!(store.commit().is_err());
+            assert!(store.read(Dataset::History).unwrap().is_none());
+
+            // Retried w/ the same rows, as save_datasets does
+            fs::remove_dir(dir.path().join("schema_version.tmp")).unwrap();
+            store.commit().unwrap();
+            assert!(store.read(Dataset::History).unwrap().is_none(), "failed commit's rows were kept staged");
+            store.append_observations(&history).unwrap();
+            store.commit().unwrap();
+            assert_eq!(prices(&store.read(Dataset::History).unwrap().unwrap()), vec![(1, 100), (2, 200)]);
+        }
+    }
+}
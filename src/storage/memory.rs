@@ -0,0 +1,160 @@
+use polars::prelude::*;
+use anyhow::Result;
+use std::collections::HashMap;
+
+use super::{upsert_runs, Dataset, ListingStore, SCHEMA_VERSION};
+
+
+/// Keeps the datasets in memory, nothing outlives the process
+///
+/// Used for dry runs and to exercise the container w/o touching disk
+#[derive(Debug, Default)]
+pub(crate) struct MemoryStore {
+    committed: HashMap<&'static str, DataFrame>,
+    /// Set by the first commit
+    version: Option<u32>,
+    staged_features: Option<DataFrame>,
+    staged_observations: Vec<DataFrame>,
+    staged_history: Option<DataFrame>,
+    staged_runs: Option<DataFrame>,
+    staged_derived: Vec<(Dataset, DataFrame)>,
+}
+
+impl ListingStore for MemoryStore {
+    fn read(&self, dataset: Dataset) -> Result<Option<DataFrame>> {
+        Ok(self.committed.get(dataset.name()).cloned())
+    }
+
+    fn recorded_version(&self) -> Result<Option<u32>> {
+        Ok(self.version)
+    }
+
+    fn upsert_features(&mut self, features: &DataFrame) -> Result<()> {
+        self.staged_features = Some(features.clone());
+        Ok(())
+    }
+
+    fn append_observations(&mut self, observations: &DataFrame) -> Result<()> {
+        self.staged_observations.push(observations.clone());
+        Ok(())
+    }
+
+    fn replace_history(&mut self, history: &DataFrame) -> Result<()> {
+        self.staged_history = Some(history.clone());
+        Ok(())
+    }
+
+    fn record_run(&mut self, run: &DataFrame) -> Result<()> {
+        self.staged_runs = Some(upsert_runs(self.staged_runs.as_ref(), run)?);
+        Ok(())
+    }
+
+    fn replace_derived(&mut self, dataset: Dataset, df: &DataFrame) -> Result<()> {
+        self.staged_derived.push((dataset, df.clone()));
+        Ok(())
+    }
+
+    /// Applied to a copy that replaces the committed datasets only once every step succeeded
+    fn commit(&mut self) -> Result<()> {
+        let committed = self.commit_staged();
+        self.discard();
+        self.committed = committed?;
+        self.version = Some(SCHEMA_VERSION);
+        Ok(())
+    }
+
+    fn discard(&mut self) {
+        self.staged_features = None;
+        self.staged_history = None;
+        self.staged_runs = None;
+        self.staged_observations.clear();
+        self.staged_derived.clear();
+    }
+}
+
+impl MemoryStore {
+    fn commit_staged(&self) -> Result<HashMap<&'static str, DataFrame>> {
+        let mut committed = self.committed.clone();
+        if let Some(features) = self.staged_features.as_ref() {
+            committed.insert(Dataset::Features.name(), features.clone());
+        }
+        if let Some(history) = self.staged_history.as_ref() {
+            committed.insert(Dataset::History.name(), history.clone());
+        }
+
+        for observations in self.staged_observations.iter() {
+            match committed.get_mut(Dataset::History.name()) {
+                Some(history) => { history.vstack_mut(observations)?; },
+                None => { committed.insert(Dataset::History.name(), observations.clone()); },
+            }
+        }
+
+        if let Some(runs) = self.staged_runs.as_ref() {
+            let runs = upsert_runs(committed.get(Dataset::Runs.name()), runs)?;
+            committed.insert(Dataset::Runs.name(), runs);
+        }
+        for (dataset, df) in self.staged_derived.iter() {
+            committed.insert(dataset.name(), df.clone());
+        }
+
+        Ok(committed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listing_structs::ScrapeRun;
+    use crate::price_events::price_events;
+    use crate::storage::test_frames::{day, features, observations, prices};
+
+    #[test]
+    fn round_trip() {
+        let mut store = MemoryStore::default();
+        assert!(store.read(Dataset::Features).unwrap().is_none());
+        assert_eq!(store.recorded_version().unwrap(), None);
+
+        let mut run = ScrapeRun::new(day(1));
+        run.partial = true;
+        store.upsert_features(&features(&[(Some(1), 77532)])).unwrap();
+        store.append_observations(&observations(run.run_id, &[(1, 100, day(1))])).unwrap();
+        store.record_run(&run.to_frame()).unwrap();
+        store.commit().unwrap();
+        assert_eq!(store.recorded_version().unwrap(), Some(SCHEMA_VERSION));
+
+        // Finishing the run replaces its row, observations add to the stored ones
+        run.partial = false;
+        run.finished_at = Some(day(2));
+        let features = features(&[(Some(1), 77532), (Some(2), 77002)]);
+        store.upsert_features(&features).unwrap();
+        store.append_observations(&observations(run.run_id, &[(1, 90, day(2))])).unwrap();
+        store.record_run(&run.to_frame()).unwrap();
+        store.commit().unwrap();
+
+        assert!(store.read(Dataset::Features).unwrap().unwrap().frame_equal_missing(&features));
+        let history = store.read(Dataset::History).unwrap().unwrap();
+        assert_eq!(prices(&history), vec![(1, 90), (1, 100)]);
+        let runs = store.read(Dataset::Runs).unwrap().unwrap();
+        assert_eq!(runs.height(), 1);
+        assert_eq!(runs.column("partial").unwrap().bool().unwrap().get(0), Some(false));
+        assert_eq!(ScrapeRun::last_finished(&runs).unwrap(), Some(day(2)));
+
+        let events = price_events(&history).unwrap();
+        store.replace_derived(Dataset::PriceEvents, &events).unwrap();
+        store.replace_history(&observations(run.run_id, &[(2, 200, day(3))])).unwrap();
+        store.commit().unwrap();
+        assert_eq!(store.read(Dataset::PriceEvents).unwrap().unwrap().height(), 1);
+        assert_eq!(prices(&store.read(Dataset::History).unwrap().unwrap()), vec![(2, 200)]);
+    }
+
+    #[test]
+    fn discard_drops_staged() {
+        let mut store = MemoryStore::default();
+        store.upsert_features(&features(&[(Some(1), 77532)])).unwrap();
+        store.append_observations(&observations(1, &[(1, 100, day(1))])).unwrap();
+        store.discard();
+        store.commit().unwrap();
+        assert!(store.read(Dataset::Features).unwrap().is_none());
+        assert!(store.read(Dataset::History).unwrap().is_none());
+    }
+}
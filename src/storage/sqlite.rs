@@ -0,0 +1,417 @@
+use polars::prelude::*;
+use rusqlite::{params_from_iter, types::Value, Connection};
+use anyhow::{Context, Result};
+use tracing::{info, warn};
+use std::fs;
+use std::path::Path;
+
+use super::{upsert_runs, Dataset, ListingStore, SCHEMA_VERSION};
+
+
+/// Embedded SQLite database holding the listing, history, run and price event datasets
+///
+/// Opened in WAL mode so other tools can read while the scraper writes
+pub(crate) struct SqliteStore {
+    conn: Connection,
+    staged_features: Option<DataFrame>,
+    staged_observations: Vec<DataFrame>,
+    staged_history: Option<DataFrame>,
+    staged_runs: Option<DataFrame>,
+    staged_derived: Vec<(Dataset, DataFrame)>,
+}
+
+impl SqliteStore {
+    /// Opens (or creates) the database, creating tables and indexes if missing
+    pub(crate) fn open(path: &Path) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create data dir {}", dir.display()))?;
+        }
+
+        let conn = Connection::open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "foreign_keys", "ON")?;
+        conn.execute_batch(&create_tables_sql())
+            .context("Failed to create sqlite tables")?;
+        add_missing_columns(&conn)?;
+        info!("Opened {}", path.display());
+
+        Ok(SqliteStore {
+            conn,
+            staged_features: None,
+            staged_observations: vec![],
+            staged_history: None,
+            staged_runs: None,
+            staged_derived: vec![],
+        })
+    }
+
+    /// Upserts listing and run rows, inserts observations not already stored
+    ///
+    /// Rows w/o a key (unclaimed legacy rows) can't be stored and are skipped
+    fn insert_rows(&self, tx: &rusqlite::Transaction, dataset: Dataset, df: &DataFrame) -> Result<()> {
+        let col_names: Vec<&str> = df.get_column_names();
+        let placeholders: Vec<String> = (1..=col_names.len()).map(|i| format!("?{}", i)).collect();
+
+        let sql = match dataset {
+            Dataset::Features => format!(
+                "INSERT INTO listings ({}) VALUES ({}) ON CONFLICT (property_id) DO UPDATE SET {}",
+                col_names.join(", "),
+                placeholders.join(", "),
+                col_names.iter().map(|name| format!("{0} = excluded.{0}", name)).collect::<Vec<_>>().join(", "),
+            ),
+            Dataset::PriceEvents => format!(
+                "INSERT INTO price_events ({}) VALUES ({})",
+                col_names.join(", "),
+                placeholders.join(", "),
+            ),
+            Dataset::History => format!(
+                "INSERT OR IGNORE INTO price_observations ({}) VALUES ({})",
+                col_names.join(", "),
+                placeholders.join(", "),
+            ),
+            Dataset::Runs => format!(
+                "INSERT INTO scrape_runs ({}) VALUES ({}) ON CONFLICT (run_id) DO UPDATE SET {}",
+                col_names.join(", "),
+                placeholders.join(", "),
+                col_names.iter().map(|name| format!("{0} = excluded.{0}", name)).collect::<Vec<_>>().join(", "),
+            ),
+        };
+
+        let mut stmt = tx.prepare(&sql)?;
+        let id_col = df.column(key_column(dataset))?;
+        let mut skipped = 0;
+        let mut written = 0;
+        for row_idx in 0..df.height() {
+            if matches!(id_col.get(row_idx)?, AnyValue::Null) {
+                skipped += 1;
+                continue;
+            }
+
+            let row = df.get_columns().iter()
+                .map(|series| series.get(row_idx).map(sql_value))
+                .collect::<PolarsResult<Vec<Value>>>()?;
+            written += stmt.execute(params_from_iter(row))?;
+        }
+
+        if skipped > 0 {
+            warn!("Skipped {} {} rows w/o a {}", skipped, dataset.name(), key_column(dataset));
+        }
+        info!("Saved {} rows to {}", written, table_name(dataset));
+        Ok(())
+    }
+}
+
+impl ListingStore for SqliteStore {
+    /// Columns come back as SQLite's integer/real/text types
+    ///
+    /// Left uncast so migrations see stored values, e.g. times from before v5 are unix seconds
+    fn read(&self, dataset: Dataset) -> Result<Option<DataFrame>> {
+        let schema = dataset.schema();
+        let col_names: Vec<&str> = schema.iter_names().map(|name| name.as_str()).collect();
+        let sql = format!(
+            "SELECT {} FROM {} ORDER BY {}",
+            col_names.join(", "), table_name(dataset), order_by(dataset)
+        );
+
+        let mut stmt = self.conn.prepare(&sql)?;
+        let mut values: Vec<Vec<AnyValue>> = vec![vec![]; col_names.len()];
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            for (i, col_values) in values.iter_mut().enumerate() {
+                col_values.push(any_value(row.get(i)?));
+            }
+        }
+
+        if values[0].is_empty() {
+            return Ok(None);
+        }
+
+        let cols = col_names.iter()
+            .zip(values)
+            .map(|(col_name, col_values)| Series::from_any_values(col_name, &col_values, false))
+            .collect::<PolarsResult<Vec<Series>>>()?;
+
+        Ok(Some(DataFrame::new(cols)?))
+    }
+
+    fn upsert_features(&mut self, features: &DataFrame) -> Result<()> {
+        self.staged_features = Some(features.clone());
+        Ok(())
+    }
+
+    fn append_observations(&mut self, observations: &DataFrame) -> Result<()> {
+        self.staged_observations.push(observations.clone());
+        Ok(())
+    }
+
+    fn replace_history(&mut self, history: &DataFrame) -> Result<()> {
+        self.staged_history = Some(history.clone());
+        Ok(())
+    }
+
+    fn record_run(&mut self, run: &DataFrame) -> Result<()> {
+        self.staged_runs = Some(upsert_runs(self.staged_runs.as_ref(), run)?);
+        Ok(())
+    }
+
+    fn replace_derived(&mut self, dataset: Dataset, df: &DataFrame) -> Result<()> {
+        self.staged_derived.push((dataset, df.clone()));
+        Ok(())
+    }
+
+    /// Stored as the database's user_version, 0 (the default) means unrecorded
+    fn recorded_version(&self) -> Result<Option<u32>> {
+        let version: u32 = self.conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        Ok(Some(version).filter(|version| *version > 0))
+    }
+
+    /// Everything staged is written in one transaction, readers never see a partial commit
+    ///
+    /// Runs go first so observations can reference them
+    fn commit(&mut self) -> Result<()> {
+        let committed = self.commit_staged();
+        self.discard();
+        committed
+    }
+
+    fn discard(&mut self) {
+        self.staged_features = None;
+        self.staged_history = None;
+        self.staged_runs = None;
+        self.staged_observations.clear();
+        self.staged_derived.clear();
+    }
+}
+
+impl SqliteStore {
+    /// Rolled back on any error, the transaction is dropped uncommitted
+    fn commit_staged(&self) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        if let Some(runs) = self.staged_runs.as_ref() {
+            self.insert_rows(&tx, Dataset::Runs, runs)?;
+        }
+        if let Some(features) = self.staged_features.as_ref() {
+            self.insert_rows(&tx, Dataset::Features, features)?;
+        }
+        if let Some(history) = self.staged_history.as_ref() {
+            tx.execute("DELETE FROM price_observations", [])?;
+            self.insert_rows(&tx, Dataset::History, history)?;
+        }
+        for observations in self.staged_observations.iter() {
+            self.insert_rows(&tx, Dataset::History, observations)?;
+        }
+        for (dataset, df) in self.staged_derived.iter() {
+            tx.execute(&format!("DELETE FROM {}", table_name(*dataset)), [])?;
+            self.insert_rows(&tx, *dataset, df)?;
+        }
+        tx.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+fn table_name(dataset: Dataset) -> &'static str {
+    match dataset {
+        Dataset::Features => "listings",
+        Dataset::History => "price_observations",
+        Dataset::Runs => "scrape_runs",
+        Dataset::PriceEvents => "price_events",
+    }
+}
+
+fn order_by(dataset: Dataset) -> &'static str {
+    match dataset {
+        Dataset::Features => "property_id",
+        Dataset::History => "date, property_id",
+        Dataset::Runs => "run_id",
+        Dataset::PriceEvents => "property_id, date",
+    }
+}
+
+/// Column rows are keyed on, rows where it's null aren't stored
+fn key_column(dataset: Dataset) -> &'static str {
+    match dataset {
+        Dataset::Features | Dataset::History | Dataset::PriceEvents => "property_id",
+        Dataset::Runs => "run_id",
+    }
+}
+
+fn sql_type(dtype: &DataType) -> &'static str {
+    match dtype {
+        DataType::Float32 | DataType::Float64 => "REAL",
+        DataType::Utf8 => "TEXT",
+        _ => "INTEGER",
+    }
+}
+
+/// Adds schema columns missing from tables created by an older version, existing rows get nulls
+///
+/// Runs recorded before v6 kept their times in unix seconds, they're converted to millis
+fn add_missing_columns(conn: &Connection) -> Result<()> {
+    for dataset in [Dataset::Runs, Dataset::Features, Dataset::History, Dataset::PriceEvents] {
+        let table = table_name(dataset);
+        let existing = conn.prepare(&format!("PRAGMA table_info({})", table))?
+            .query_map([], |row| row.get::<_, String>(1))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+
+        if dataset == Dataset::Runs && !existing.iter().any(|name| name == "crate_version") {
+            conn.execute("UPDATE scrape_runs SET started_at = started_at * 1000, finished_at = finished_at * 1000", [])
+                .context("Failed to convert scrape run times")?;
+        }
+
+        for (col_name, dtype) in dataset.schema().iter() {
+            if existing.iter().any(|name| name == col_name.as_str()) {
+                continue;
+            }
+            info!("Adding column {} to {}", col_name, table);
+            conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, col_name, sql_type(dtype)), [])
+                .with_context(|| format!("Failed to add {} to {}", col_name, table))?;
+        }
+    }
+    Ok(())
+}
+
+/// Table columns are generated from the dataset schemas so the two can't drift
+fn create_tables_sql() -> String {
+    let sql_columns = |dataset: Dataset| -> Vec<String> {
+        dataset.schema().iter()
+            .map(|(col_name, dtype)| match col_name.as_str() {
+                "property_id" if dataset == Dataset::Features => format!("{} INTEGER PRIMARY KEY", col_name),
+                "property_id" => format!("{} INTEGER NOT NULL REFERENCES listings (property_id)", col_name),
+                "run_id" if dataset == Dataset::Runs => format!("{} INTEGER PRIMARY KEY", col_name),
+                "run_id" => format!("{} INTEGER REFERENCES scrape_runs (run_id)", col_name),
+                _ => format!("{} {}", col_name, sql_type(dtype)),
+            })
+            .collect()
+    };
+
+    format!(
+        "CREATE TABLE IF NOT EXISTS scrape_runs (
+            {}
+        );
+        CREATE TABLE IF NOT EXISTS listings (
+            {}
+        );
+        CREATE INDEX IF NOT EXISTS listings_zip ON listings (zip);
+        CREATE TABLE IF NOT EXISTS price_observations (
+            observation_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            {},
+            UNIQUE (property_id, date)
+        );
+        CREATE INDEX IF NOT EXISTS price_observations_property_id ON price_observations (property_id);
+        CREATE INDEX IF NOT EXISTS price_observations_date ON price_observations (date);
+        CREATE TABLE IF NOT EXISTS price_events (
+            {}
+        );",
+        sql_columns(Dataset::Runs).join(",\n            "),
+        sql_columns(Dataset::Features).join(",\n            "),
+        sql_columns(Dataset::History).join(",\n            "),
+        sql_columns(Dataset::PriceEvents).join(",\n            "),
+    )
+}
+
+fn any_value(value: Value) -> AnyValue<'static> {
+    match value {
+        Value::Null => AnyValue::Null,
+        Value::Integer(v) => AnyValue::Int64(v),
+        Value::Real(v) => AnyValue::Float64(v),
+        Value::Text(v) => AnyValue::Utf8Owned(v.into()),
+        Value::Blob(_) => AnyValue::Null,
+    }
+}
+
+fn sql_value(value: AnyValue) -> Value {
+    match value {
+        AnyValue::Null => Value::Null,
+        AnyValue::Boolean(v) => Value::Integer(v as i64),
+        AnyValue::UInt8(v) => Value::Integer(v as i64),
+        AnyValue::UInt32(v) => Value::Integer(v as i64),
+        AnyValue::UInt64(v) => Value::Integer(v as i64),
+        AnyValue::Int32(v) => Value::Integer(v as i64),
+        AnyValue::Int64(v) => Value::Integer(v),
+        AnyValue::Float32(v) => Value::Real(v as f64),
+        AnyValue::Float64(v) => Value::Real(v),
+        AnyValue::Utf8(v) => Value::Text(v.to_string()),
+        // Stored as epoch millis, the schema dtype carries the unit and timezone
+        AnyValue::Datetime(v, _, _) => Value::Integer(v),
+        other => Value::Text(other.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listing_structs::ScrapeRun;
+    use crate::price_events::price_events;
+    use crate::storage::conform_to_schema;
+    use crate::storage::test_frames::{day, features, observations, prices};
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data/listings.db");
+        let mut run = ScrapeRun::new(day(1));
+        let features = features(&[(Some(1), 77532), (Some(2), 77002)]);
+        {
+            let mut store = SqliteStore::open(&path).unwrap();
+            assert_eq!(store.recorded_version().unwrap(), None);
+
+            run.partial = true;
+            store.upsert_features(&features).unwrap();
+            store.append_observations(&observations(run.run_id, &[(1, 100, day(1)), (2, 200, day(1))])).unwrap();
+            store.record_run(&run.to_frame()).unwrap();
+            store.commit().unwrap();
+
+            run.partial = false;
+            run.finished_at = Some(day(2));
+            store.append_observations(&observations(run.run_id, &[(1, 90, day(2))])).unwrap();
+            store.record_run(&run.to_frame()).unwrap();
+            store.commit().unwrap();
+        }
+
+        // A second open reads back what the first committed
+        let mut store = SqliteStore::open(&path).unwrap();
+        assert_eq!(store.recorded_version().unwrap(), Some(SCHEMA_VERSION));
+        let stored = store.read(Dataset::Features).unwrap().unwrap();
+        let (stored, _) = conform_to_schema(&stored, &Dataset::Features.schema()).unwrap();
+        assert!(stored.frame_equal_missing(&features));
+        let history = store.read(Dataset::History).unwrap().unwrap();
+        assert_eq!(prices(&history), vec![(1, 90), (1, 100), (2, 200)]);
+        let runs = store.read(Dataset::Runs).unwrap().unwrap();
+        assert_eq!(runs.height(), 1);
+        assert_eq!(ScrapeRun::last_finished(&runs).unwrap(), Some(day(2)));
+
+        let (history, _) = conform_to_schema(&history, &Dataset::History.schema()).unwrap();
+        store.replace_derived(Dataset::PriceEvents, &price_events(&history).unwrap()).unwrap();
+        store.commit().unwrap();
+        assert_eq!(store.read(Dataset::PriceEvents).unwrap().unwrap().height(), 1);
+    }
+
+    #[test]
+    fn repeated_observations_stored_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = SqliteStore::open(&dir.path().join("listings.db")).unwrap();
+        let run = ScrapeRun::new(day(1));
+        let observed = observations(run.run_id, &[(1, 100, day(1))]);
+        store.record_run(&run.to_frame()).unwrap();
+        store.upsert_features(&features(&[(Some(1), 77532)])).unwrap();
+        store.append_observations(&observed).unwrap();
+        store.commit().unwrap();
+        store.append_observations(&observed).unwrap();
+        store.commit().unwrap();
+        assert_eq!(store.read(Dataset::History).unwrap().unwrap().height(), 1);
+    }
+
+    #[test]
+    fn failed_commit_rolls_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = SqliteStore::open(&dir.path().join("listings.db")).unwrap();
+        store.upsert_features(&features(&[(Some(1), 77532)])).unwrap();
+        store.append_observations(&DataFrame::new(vec![Series::new("no_such_col", [1u32])]).unwrap()).unwrap();
+        assert!(store.commit().is_err());
+        assert!(store.read(Dataset::Features).unwrap().is_none());
+        assert_eq!(store.recorded_version().unwrap(), None);
+    }
+}
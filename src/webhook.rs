@@ -0,0 +1,294 @@
+use anyhow::{anyhow, bail, Result};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::Sha256;
+use tracing::{info, warn};
+use std::time::Duration;
+
+use crate::alerts::{Alert, ListingEvent, Notifier, NotifyFuture};
+
+
+/// Hex HMAC-SHA256 of the body, prefixed w/ `sha256=`
+const SIGNATURE_HEADER: &str = "X-Signature-256";
+const EVENT_HEADER: &str = "X-Event-Type";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A URL events are POSTed to, from the alert config's `webhooks` list
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct WebhookConfig {
+    pub(crate) url: String,
+    /// Event types sent (`new_listing`, `price_change`, `alert`), all if unset
+    pub(crate) events: Option<Vec<String>>,
+    /// Key bodies are signed w/, unsigned if unset
+    pub(crate) secret: Option<String>,
+    /// Body w/ `{{field}}` placeholders for the event's fields, the event's JSON if unset
+    ///
+    /// Strings are inserted JSON escaped w/o quotes, so they can sit inside a quoted template string
+    pub(crate) template: Option<String>,
+    /// Retries after a connection error, 429 or 5xx response
+    #[serde(default = "default_max_retries")]
+    pub(crate) max_retries: u32,
+    /// Delay before the first retry, doubled for each after
+    #[serde(default = "default_retry_delay_ms")]
+    pub(crate) retry_delay_ms: u64,
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+fn default_retry_delay_ms() -> u64 {
+    500
+}
+
+impl WebhookConfig {
+    fn sends(&self, event_type: &str) -> bool {
+        self.events.as_ref().is_none_or(|events| events.iter().any(|event| event == event_type))
+    }
+}
+
+/// POSTs one JSON body per event to a webhook URL
+pub(crate) struct WebhookNotifier {
+    config: WebhookConfig,
+}
+
+impl WebhookNotifier {
+    pub(crate) fn new(config: WebhookConfig) -> Self {
+        WebhookNotifier { config }
+    }
+
+    /// Events are JSON objects w/ an `event` type field
+    async fn post_all(&self, events: Vec<Value>) -> Result<()> {
+        let events: Vec<Value> = events.into_iter()
+            .filter(|event| self.config.sends(event_type(event)))
+            .collect();
+        if events.is_empty() {
+            return Ok(());
+        }
+
+        let client = reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build()?;
+        let mut failed = 0;
+        for event in events.iter() {
+            if let Err(e) = self.post(&client, event).await {
+                warn!("Webhook {} failed for {} event: {:#}", self.config.url, event_type(event), e);
+                failed += 1;
+            }
+        }
+        info!("Posted {} of {} events to {}", events.len() - failed, events.len(), self.config.url);
+        match failed {
+            0 => Ok(()),
+            n => Err(anyhow!("{} of {} posts failed", n, events.len())),
+        }
+    }
+
+    async fn post(&self, client: &reqwest::Client, event: &Value) -> Result<()> {
+        let body = match self.config.template.as_ref() {
+            Some(template) => render_template(template, event),
+            None => event.to_string(),
+        };
+        let signature = self.config.secret.as_ref().map(|secret| sign(secret, body.as_bytes()));
+
+        let mut attempt = 0;
+        loop {
+            let mut request = client.post(&self.config.url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .header(EVENT_HEADER, event_type(event))
+                .body(body.clone());
+            if let Some(signature) = signature.as_ref() {
+                request = request.header(SIGNATURE_HEADER, signature);
+            }
+
+            let retry_reason = match request.send().await {
+                Ok(response) if response.status().is_success() => return Ok(()),
+                Ok(response) => {
+                    let status = response.status();
+                    if !status.is_server_error() && status != reqwest::StatusCode::TOO_MANY_REQUESTS {
+                        bail!("Responded {}", status);
+                    }
+                    format!("responded {}", status)
+                }
+                Err(e) => e.to_string(),
+            };
+
+            if attempt >= self.config.max_retries {
+                bail!("Gave up after {} attempts, last {}", attempt + 1, retry_reason);
+            }
+            let delay = Duration::from_millis(self.config.retry_delay_ms.saturating_mul(1 << attempt.min(16)));
+            warn!("Webhook {} {}, retrying in {:?}", self.config.url, retry_reason, delay);
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+impl Notifier for WebhookNotifier {
+    fn name(&self) -> &str {
+        &self.config.url
+    }
+
+    fn notify<'a>(&'a self, alerts: &'a [Alert]) -> NotifyFuture<'a> {
+        Box::pin(async move {
+            let events = alerts.iter()
+                .map(|alert| {
+                    let mut event = serde_json::to_value(alert)?;
+                    event["event"] = Value::from("alert");
+                    Ok(event)
+                })
+                .collect::<Result<Vec<Value>>>()?;
+            self.post_all(events).await
+        })
+    }
+
+    fn notify_listing_events<'a>(&'a self, events: &'a [ListingEvent]) -> NotifyFuture<'a> {
+        Box::pin(async move {
+            let events = events.iter()
+                .map(serde_json::to_value)
+                .collect::<serde_json::Result<Vec<Value>>>()?;
+            self.post_all(events).await
+        })
+    }
+}
+
+fn event_type(event: &Value) -> &str {
+    event["event"].as_str().unwrap_or_default()
+}
+
+/// `sha256=<hex hmac>`, receivers recompute it over the raw body w/ the shared secret
+pub(crate) fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac takes keys of any length");
+    mac.update(body);
+    let digest: String = mac.finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("sha256={}", digest)
+}
+
+/// Replaces each `{{field}}` w/ the event's value, unknown fields become empty
+fn render_template(template: &str, event: &Value) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start..].find("}}") else { break };
+        rendered.push_str(&rest[..start]);
+        let field = rest[start + 2..start + len].trim();
+        match &event[field] {
+            Value::Null => {}
+            // Escaped w/o the surrounding quotes
+            Value::String(value) => {
+                let quoted = Value::from(value.as_str()).to_string();
+                rendered.push_str(&quoted[1..quoted.len() - 1]);
+            }
+            value => rendered.push_str(&value.to_string()),
+        }
+        rest = &rest[start + len + 2..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread::JoinHandle;
+
+    fn config(url: String) -> WebhookConfig {
+        WebhookConfig {
+            url,
+            events: None,
+            secret: Some(String::from("key")),
+            template: None,
+            max_retries: 2,
+            retry_delay_ms: 1,
+        }
+    }
+
+    /// Answers one request per status, in order, then returns the requests received
+    fn serve(statuses: Vec<u16>) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let handle = std::thread::spawn(move || {
+            let mut requests = vec![];
+            for status in statuses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some(len) = line.to_lowercase().strip_prefix("content-length:") {
+                        content_length = len.trim().parse().unwrap();
+                    }
+                    request.push_str(&line);
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                request.push_str(&String::from_utf8(body).unwrap());
+                requests.push(request);
+                write!(stream, "HTTP/1.1 {} X\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status).unwrap();
+            }
+            requests
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn sign_matches_hmac_sha256() {
+        assert_eq!(
+            sign("key", b"The quick brown fox jumps over the lazy dog"),
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[test]
+    fn render_template_fills_fields() {
+        let event = serde_json::json!({"event": "alert", "addr_str": "1 \"A\" St", "new_price": 250000});
+        assert_eq!(
+            render_template(r#"{"text": "{{ addr_str }} now {{new_price}}{{missing}}"}"#, &event),
+            r#"{"text": "1 \"A\" St now 250000"}"#
+        );
+        assert_eq!(render_template("no {{close", &event), "no {{close");
+    }
+
+    #[tokio::test]
+    async fn post_retries_server_errors() {
+        let (url, server) = serve(vec![503, 429, 200]);
+        let notifier = WebhookNotifier::new(config(url));
+        let event = serde_json::json!({"event": "new_listing", "property_id": 1});
+        notifier.post_all(vec![event.clone()]).await.unwrap();
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 3);
+        let signature = sign("key", event.to_string().as_bytes());
+        for request in requests {
+            assert!(request.to_lowercase().contains(&format!("x-signature-256: {}", signature)));
+            assert!(request.ends_with(&event.to_string()));
+        }
+    }
+
+    #[tokio::test]
+    async fn post_gives_up() {
+        let (url, server) = serve(vec![500, 500, 500]);
+        let notifier = WebhookNotifier::new(config(url));
+        assert!(notifier.post_all(vec![serde_json::json!({"event": "alert"})]).await.is_err());
+        assert_eq!(server.join().unwrap().len(), 3);
+
+        // Client errors aren't retried
+        let (url, server) = serve(vec![400]);
+        let notifier = WebhookNotifier::new(config(url));
+        assert!(notifier.post_all(vec![serde_json::json!({"event": "alert"})]).await.is_err());
+        assert_eq!(server.join().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn post_skips_unsent_events() {
+        let mut config = config(String::from("http://127.0.0.1:1/unused"));
+        config.events = Some(vec![String::from("alert")]);
+        let notifier = WebhookNotifier::new(config);
+        notifier.post_all(vec![serde_json::json!({"event": "new_listing"})]).await.unwrap();
+    }
+}